@@ -1,28 +1,23 @@
+use crate::api::session::TrackQuery;
 use crate::asr::client::SpeechModel;
-use crate::asr::processor::{ProcessResponse, WaitForResponse};
-use crate::garbage::collector::GarbageCollector;
-use crate::webrtc::CloseSession;
-use crate::{
-    AsrProcessor, SessionConfig, UserAsrProcessorStorage, UserId, UserSessionStorage, VkApi,
-};
-use actix::Addr;
+use crate::asr::launcher::{AsrLauncher, AsrRequest};
+use crate::asr::processor::{AsrState, GetState, ProcessResponse, WaitForResponse};
+use crate::webhook::dispatcher::{check_callback, WebhookConfig};
+use crate::webrtc::{CloseReason, CloseSession, Closed, HasTrack};
+use crate::{UserAsrProcessorStorage, UserId, UserSessionStorage};
 use actix_web::http::StatusCode;
-use actix_web::{post, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use actix_web::{get, post, web, HttpMessage, HttpRequest, HttpResponse, Responder};
 use log::error;
 use serde::{Deserialize, Serialize};
+use url::Url;
 use uuid::Uuid;
-use vkclient::upload::VkUploader;
 
-#[allow(clippy::too_many_arguments)]
 #[post("/asr")]
 pub async fn api_text_to_speech(
     req: HttpRequest,
     user_session_storage: web::Data<UserSessionStorage>,
-    user_asr_processor_storage: web::Data<UserAsrProcessorStorage>,
-    vk_client: web::Data<VkApi>,
-    vk_uploader: web::Data<VkUploader>,
-    config: web::Data<SessionConfig>,
-    garbage_collector: web::Data<Addr<GarbageCollector>>,
+    asr_launcher: web::Data<AsrLauncher>,
+    webhook_config: web::Data<WebhookConfig>,
     session: web::Json<ProcessAsrRequest>,
 ) -> impl Responder {
     let user_id = match req.extensions().get::<UserId>() {
@@ -34,17 +29,31 @@ pub async fn api_text_to_speech(
         Some(&uid) => uid,
     };
 
-    let asr_processor_storage = user_asr_processor_storage
-        .entry(user_id)
-        .or_default()
-        .clone();
+    if let Some(url) = &session.callback_url {
+        if webhook_config.secret.is_none() {
+            return HttpResponse::build(StatusCode::BAD_REQUEST).json(ProcessAsrError {
+                error: "webhooks are disabled",
+            });
+        }
+        if let Err(error) = check_callback(&webhook_config, url).await {
+            return HttpResponse::build(StatusCode::BAD_REQUEST).json(ProcessAsrError { error });
+        }
+    }
 
     let session_storage = user_session_storage.entry(user_id).or_default().clone();
 
-    if !asr_processor_storage.contains_key(&session.session_id) {
+    if !asr_launcher.is_started(user_id, session.session_id, session.track) {
         match session_storage.get(&session.session_id) {
             Some(s) if s.connected() => {
-                let _ = s.send(CloseSession).await;
+                // recording isn't interrupted by the request of another track
+                if let Ok(false) = s.send(HasTrack(session.track)).await {
+                    return HttpResponse::build(StatusCode::NOT_FOUND).json(ProcessAsrError {
+                        error: "track wasn't recorded",
+                    });
+                }
+                if let Ok(Closed(rx)) = s.send(CloseSession(CloseReason::Recognition)).await {
+                    let _ = rx.await;
+                }
             }
             None => {
                 return HttpResponse::build(StatusCode::NOT_FOUND).json(ProcessAsrError {
@@ -55,20 +64,33 @@ pub async fn api_text_to_speech(
         }
     }
 
-    let asr_processor = asr_processor_storage
-        .entry(session.session_id)
-        .or_insert_with(|| {
-            AsrProcessor::new(
-                session.session_id,
-                user_id,
-                vk_client.into_inner(),
-                vk_uploader.into_inner(),
-                config.dir.clone(),
-                garbage_collector.into_inner(),
-                session.speech,
-            )
-        })
-        .downgrade();
+    let Some(asr_processor) = asr_launcher.launch(AsrRequest {
+        user_id,
+        session_id: session.session_id,
+        track: session.track,
+        speech_model: session.speech,
+        callback: session.callback_url.clone(),
+    }) else {
+        return HttpResponse::build(StatusCode::NOT_FOUND).json(ProcessAsrError {
+            error: "track wasn't recorded",
+        });
+    };
+
+    if matches!(session.mode, AsrMode::Async) {
+        return match asr_processor.send(GetState).await {
+            Ok(state) => HttpResponse::build(StatusCode::ACCEPTED).json(AsrStateResponse {
+                session_id: session.session_id,
+                track: session.track,
+                state,
+            }),
+            Err(e) => {
+                error!(target: "api_asr", "error on getting asr state {}", e);
+                HttpResponse::build(StatusCode::SERVICE_UNAVAILABLE).json(ProcessAsrError {
+                    error: e.to_string(),
+                })
+            }
+        };
+    }
 
     let ProcessResponse(rx) = match asr_processor.send(WaitForResponse).await {
         Ok(r) => r,
@@ -94,19 +116,81 @@ pub async fn api_text_to_speech(
         Ok(t) => t.clone(),
         Err(e) => {
             error!(target: "api_asr", "error on processing asr {}", e);
-            return HttpResponse::build(StatusCode::SERVICE_UNAVAILABLE).json(ProcessAsrError {
-                error: e.to_string(),
-            });
+            return HttpResponse::build(e.status_code()).json(e);
         }
     };
 
     HttpResponse::Ok().json(ProcessAsrResponse { text })
 }
 
+#[get("/asr/{session_id}")]
+pub async fn api_get_asr_state(
+    req: HttpRequest,
+    user_asr_processor_storage: web::Data<UserAsrProcessorStorage>,
+    path: web::Path<(Uuid,)>,
+    query: web::Query<TrackQuery>,
+) -> impl Responder {
+    let user_id = match req.extensions().get::<UserId>() {
+        None => {
+            return HttpResponse::build(StatusCode::UNAUTHORIZED).json(ProcessAsrError {
+                error: "authorization is failed",
+            });
+        }
+        Some(&uid) => uid,
+    };
+
+    let (session_id,) = path.into_inner();
+    let track = query.track;
+
+    let asr_processor = match user_asr_processor_storage
+        .get(&user_id)
+        .and_then(|s| s.get(&(session_id, track)).map(|p| p.clone()))
+    {
+        Some(p) => p,
+        None => {
+            return HttpResponse::build(StatusCode::NOT_FOUND).json(ProcessAsrError {
+                error: "speech recognition wasn't started",
+            });
+        }
+    };
+
+    match asr_processor.send(GetState).await {
+        Ok(state) => HttpResponse::Ok().json(AsrStateResponse {
+            session_id,
+            track,
+            state,
+        }),
+        Err(e) => {
+            error!(target: "api_asr", "error on getting asr state {}", e);
+            HttpResponse::build(StatusCode::SERVICE_UNAVAILABLE).json(ProcessAsrError {
+                error: e.to_string(),
+            })
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct ProcessAsrRequest {
     session_id: Uuid,
+    /// Number of the recorded session track, the first one by default
+    #[serde(default)]
+    track: usize,
     speech: SpeechModel,
+    #[serde(default)]
+    mode: AsrMode,
+    /// Recognition result is posted to this url, overrides `WebhookConfig::url`
+    #[serde(default)]
+    callback_url: Option<Url>,
+}
+
+/// `Sync` holds the request until recognition is done,
+/// `Async` returns the job state immediately, it can be polled by `api_get_asr_state`.
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AsrMode {
+    #[default]
+    Sync,
+    Async,
 }
 
 #[derive(Serialize)]
@@ -114,7 +198,341 @@ pub struct ProcessAsrResponse {
     text: String,
 }
 
+#[derive(Serialize)]
+pub struct AsrStateResponse {
+    session_id: Uuid,
+    track: usize,
+    #[serde(flatten)]
+    state: AsrState,
+}
+
 #[derive(Serialize)]
 pub struct ProcessAsrError<E> {
     error: E,
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::api::test_context::{close_session, TestContext};
+    use crate::asr::client::VkError;
+    use crate::asr::mock::{MockScript, MockStatus, MockVkAsr};
+    use crate::webhook::dispatcher::WebhookConfig;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web};
+    use serde_json::{json, Value};
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    async fn call_asr(ctx: &TestContext, session_id: Uuid) -> (StatusCode, Value) {
+        ctx.call(
+            test::TestRequest::post()
+                .uri("/session/asr")
+                .set_json(json!({ "session_id": session_id, "speech": "neutral" })),
+        )
+        .await
+    }
+
+    /// Recognizes a closed session by the mock of VK API, the mock is returned to check its calls.
+    async fn recognize_by_mock(
+        script: MockScript,
+        deadline: Duration,
+    ) -> (StatusCode, Value, MockVkAsr) {
+        let mock = MockVkAsr::start(script).await.unwrap();
+        let ctx = TestContext::new(mock.url.clone(), deadline);
+        let session_id = ctx.create_closed_session().await;
+
+        let (status, body) = call_asr(&ctx, session_id).await;
+        (status, body, mock)
+    }
+
+    #[actix_web::test]
+    async fn recognize_with_mock_vk() {
+        let (status, body, mock) = recognize_by_mock(
+            MockScript {
+                statuses: vec![
+                    MockStatus::Processing,
+                    MockStatus::Finished {
+                        text: "hello world".to_string(),
+                    },
+                ],
+                ..Default::default()
+            },
+            Duration::from_secs(10),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "text": "hello world" }));
+        assert_eq!(mock.calls.upload.load(Ordering::SeqCst), 1);
+        assert_eq!(mock.calls.check_status.load(Ordering::SeqCst), 2);
+        mock.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn recognition_error_from_mock_vk() {
+        let (status, body, mock) = recognize_by_mock(
+            MockScript {
+                statuses: vec![MockStatus::RecognitionError],
+                ..Default::default()
+            },
+            Duration::from_secs(10),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["code"], "recognition_error");
+        assert!(body["error"].is_string());
+        mock.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn unknown_session() {
+        let ctx = TestContext::offline();
+
+        let (status, _) = call_asr(&ctx, Uuid::new_v4()).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn internal_callback_is_rejected() {
+        let mut ctx = TestContext::offline();
+        ctx.webhook_config = web::Data::new(WebhookConfig {
+            secret: Some("secret".to_string()),
+            ..WebhookConfig::clone(&ctx.webhook_config)
+        });
+
+        let call = |callback_url: &'static str| {
+            test::TestRequest::post()
+                .uri("/session/asr")
+                .set_json(json!({
+                    "session_id": Uuid::new_v4(),
+                    "speech": "neutral",
+                    "callback_url": callback_url
+                }))
+        };
+
+        for callback_url in [
+            "http://127.0.0.1/callback",
+            "http://localhost:8080/callback",
+            "http://169.254.169.254/latest/meta-data",
+            "http://10.0.0.1/callback",
+            "http://[::1]/callback",
+            "http://[::ffff:192.168.0.1]/callback",
+        ] {
+            let (status, body) = ctx.call(call(callback_url)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", callback_url);
+            assert_eq!(body["error"], "callback host isn't public");
+        }
+
+        ctx.webhook_config = web::Data::new(WebhookConfig {
+            allowed_hosts: Some(["hooks.example.com".to_string()].into()),
+            ..WebhookConfig::clone(&ctx.webhook_config)
+        });
+        let (status, body) = ctx.call(call("https://evil.example.com/callback")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "callback host isn't allowed");
+        // allowed callback passes the check, the session is unknown
+        let (status, _) = ctx.call(call("https://hooks.example.com/callback")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn stuck_task_hits_deadline() {
+        let (status, body, mock) = recognize_by_mock(
+            MockScript {
+                statuses: vec![MockStatus::Processing],
+                ..Default::default()
+            },
+            Duration::from_millis(300),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(body["code"], "timeout");
+        assert!(mock.calls.check_status.load(Ordering::SeqCst) > 1);
+        mock.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn transient_upload_failure_is_retried() {
+        let (status, body, mock) = recognize_by_mock(
+            MockScript {
+                upload_statuses: vec![502],
+                statuses: vec![MockStatus::Finished {
+                    text: "hello world".to_string(),
+                }],
+                ..Default::default()
+            },
+            Duration::from_secs(10),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "text": "hello world" }));
+        assert_eq!(mock.calls.upload.load(Ordering::SeqCst), 2);
+        mock.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn permanent_vk_error_is_not_retried() {
+        let (status, body, mock) = recognize_by_mock(
+            MockScript {
+                process_error: Some(VkError {
+                    error_code: 100,
+                    error_msg: "one of the parameters specified was missing or invalid".to_string(),
+                }),
+                ..Default::default()
+            },
+            Duration::from_secs(10),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(body["code"], "vk_api_error");
+        assert_eq!(body["vk_error_code"], 100);
+        assert_eq!(mock.calls.process.load(Ordering::SeqCst), 1);
+        mock.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn vk_task_errors_are_mapped() {
+        for (vk_status, status, code) in [
+            (
+                MockStatus::TranscodingError,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "transcoding_error",
+            ),
+            (
+                MockStatus::InternalError,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+            ),
+        ] {
+            let (actual_status, body, mock) = recognize_by_mock(
+                MockScript {
+                    statuses: vec![vk_status],
+                    ..Default::default()
+                },
+                Duration::from_secs(10),
+            )
+            .await;
+
+            assert_eq!(actual_status, status);
+            assert_eq!(body["code"], code);
+            mock.stop().await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn async_job_is_polled() {
+        let mock = MockVkAsr::start(MockScript {
+            statuses: vec![
+                MockStatus::Processing,
+                MockStatus::Finished {
+                    text: "hello world".to_string(),
+                },
+            ],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let ctx = TestContext::new(mock.url.clone(), Duration::from_secs(10));
+        let session_id = ctx.create_closed_session().await;
+
+        let (status, body) = ctx
+            .call(
+                test::TestRequest::post()
+                    .uri("/session/asr")
+                    .set_json(json!({
+                        "session_id": session_id,
+                        "speech": "neutral",
+                        "mode": "async"
+                    })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body["session_id"], json!(session_id));
+        assert!(matches!(
+            body["state"].as_str(),
+            Some("pending" | "processing")
+        ));
+
+        let body = loop {
+            let (status, body) = ctx
+                .call(test::TestRequest::get().uri(&format!("/session/asr/{}", session_id)))
+                .await;
+            assert_eq!(status, StatusCode::OK);
+            if body["state"] != "pending" && body["state"] != "processing" {
+                break body;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+        };
+
+        assert_eq!(
+            body,
+            json!({
+                "session_id": session_id,
+                "track": 0,
+                "state": "finished",
+                "text": "hello world"
+            })
+        );
+        mock.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn unknown_track() {
+        let ctx = TestContext::offline();
+        let session_id = ctx.create_closed_session().await;
+
+        let (status, body) = ctx
+            .call(
+                test::TestRequest::post()
+                    .uri("/session/asr")
+                    .set_json(json!({
+                        "session_id": session_id,
+                        "track": 1,
+                        "speech": "neutral"
+                    })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "track wasn't recorded");
+    }
+
+    #[actix_web::test]
+    async fn unknown_track_keeps_session_writing() {
+        let ctx = TestContext::offline();
+        let (session_id, session) = ctx.create_session().await;
+
+        let (status, body) = ctx
+            .call(
+                test::TestRequest::post()
+                    .uri("/session/asr")
+                    .set_json(json!({
+                        "session_id": session_id,
+                        "track": 1,
+                        "speech": "neutral"
+                    })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "track wasn't recorded");
+        assert!(session.connected());
+
+        close_session(&session).await;
+    }
+
+    #[actix_web::test]
+    async fn unknown_asr_job() {
+        let ctx = TestContext::offline();
+
+        let (status, _) = ctx
+            .call(test::TestRequest::get().uri(&format!("/session/asr/{}", Uuid::new_v4())))
+            .await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,184 @@
+use crate::events::broker::{EventBroker, Subscribe, Subscription};
+use crate::events::SessionEvent;
+use crate::{UserId, UserSessionStorage};
+use actix::Addr;
+use actix_web::http::header::{CacheControl, CacheDirective, ContentEncoding};
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{get, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use futures::future::ready;
+use futures::{stream, StreamExt};
+use log::error;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Comment sent to keep idle connections open through proxies.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Streams lifecycle events of the session as `text/event-stream`.
+/// Events happened before the connection are sent first.
+/// Stream is closed when the session is cleared by the garbage collector.
+#[get("/events/{session_id}")]
+pub async fn api_session_events(
+    req: HttpRequest,
+    user_session_storage: web::Data<UserSessionStorage>,
+    events: web::Data<Addr<EventBroker>>,
+    path: web::Path<(Uuid,)>,
+) -> impl Responder {
+    let user_id = match req.extensions().get::<UserId>() {
+        None => {
+            return HttpResponse::build(StatusCode::UNAUTHORIZED).json(EventsError {
+                error: "authorization is failed",
+            });
+        }
+        Some(&uid) => uid,
+    };
+
+    let (session_id,) = path.into_inner();
+
+    if !user_session_storage
+        .get(&user_id)
+        .is_some_and(|s| s.contains_key(&session_id))
+    {
+        return HttpResponse::build(StatusCode::NOT_FOUND).json(EventsError {
+            error: "webrtc session wasn't created",
+        });
+    }
+
+    let Subscription(rx) = match events.send(Subscribe(session_id)).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!(target: "api_events", "error on subscribing to events {}", e);
+            return HttpResponse::build(StatusCode::SERVICE_UNAVAILABLE).json(EventsError {
+                error: e.to_string(),
+            });
+        }
+    };
+
+    let events = rx
+        .map(|event| Some(encode_event(&event)))
+        .chain(stream::once(ready(None)));
+
+    let keep_alive = stream::unfold(
+        actix_web::rt::time::interval(KEEP_ALIVE_INTERVAL),
+        |mut interval| async move {
+            interval.tick().await;
+            Some((Some(Bytes::from_static(b": keep-alive\n\n")), interval))
+        },
+    );
+
+    let body = stream::select(events, keep_alive)
+        .take_while(|chunk| ready(chunk.is_some()))
+        .filter_map(ready)
+        .map(Ok::<_, Infallible>);
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        // compression buffers chunks, so events would be delayed
+        .insert_header(ContentEncoding::Identity)
+        .streaming(body)
+}
+
+fn encode_event(event: &SessionEvent) -> Bytes {
+    match serde_json::to_string(event) {
+        Ok(data) => Bytes::from(format!("event: {}\ndata: {}\n\n", event.name(), data)),
+        Err(e) => {
+            error!(target: "api_events", "fail to serialize event {}", e);
+            Bytes::new()
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct EventsError<E> {
+    error: E,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::test_context::TestContext;
+    use crate::asr::mock::{MockScript, MockStatus, MockVkAsr};
+    use actix_web::body::MessageBody;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use serde_json::json;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    /// Reads event names from the SSE stream until `last` is received.
+    async fn read_events(ctx: &TestContext, session_id: Uuid, last: &str) -> Vec<String> {
+        let resp = ctx
+            .call_service(test::TestRequest::get().uri(&format!("/session/events/{}", session_id)))
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let mut body = Box::pin(resp.into_body());
+        let mut names = vec![];
+        while names.last().is_none_or(|n| n != last) {
+            let chunk = std::future::poll_fn(|cx| body.as_mut().poll_next(cx))
+                .await
+                .expect("event stream is closed")
+                .unwrap();
+            let chunk = String::from_utf8(chunk.to_vec()).unwrap();
+            names.extend(
+                chunk
+                    .lines()
+                    .filter_map(|l| l.strip_prefix("event: "))
+                    .map(ToString::to_string),
+            );
+        }
+        names
+    }
+
+    #[actix_web::test]
+    async fn lifecycle_events_are_streamed() {
+        let mock = MockVkAsr::start(MockScript {
+            statuses: vec![
+                MockStatus::Processing,
+                MockStatus::Finished {
+                    text: "hello world".to_string(),
+                },
+            ],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let ctx = TestContext::new(mock.url.clone(), Duration::from_secs(10));
+        let session_id = ctx.create_closed_session().await;
+
+        let (status, _) = ctx
+            .call(
+                test::TestRequest::post()
+                    .uri("/session/asr")
+                    .set_json(json!({ "session_id": session_id, "speech": "neutral" })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        assert_eq!(
+            read_events(&ctx, session_id, "asr_finished").await,
+            [
+                "session_closed",
+                "asr_upload_started",
+                "asr_task_assigned",
+                "asr_processing",
+                "asr_finished"
+            ]
+        );
+        mock.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn events_of_unknown_session() {
+        let ctx = TestContext::offline();
+
+        let (status, _) = ctx
+            .call(test::TestRequest::get().uri(&format!("/session/events/{}", Uuid::new_v4())))
+            .await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
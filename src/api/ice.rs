@@ -0,0 +1,218 @@
+use crate::api::session::{connected_session, SessionConfig};
+use crate::webrtc::ice::IceServer;
+use crate::webrtc::{AddRemoteCandidate, CandidateResponse, GetLocalCandidates, LocalCandidates};
+use crate::{UserId, UserSessionStorage};
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use log::error;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+
+#[get("/ice_servers")]
+pub async fn api_get_ice_servers(
+    req: HttpRequest,
+    config: web::Data<SessionConfig>,
+) -> impl Responder {
+    let user_id = match req.extensions().get::<UserId>() {
+        None => {
+            return HttpResponse::build(StatusCode::UNAUTHORIZED).json(IceError {
+                error: "authorization is failed",
+            });
+        }
+        Some(&uid) => uid,
+    };
+
+    HttpResponse::Ok().json(IceServersResponse {
+        ice_servers: config.ice.servers(user_id),
+    })
+}
+
+#[post("/candidate")]
+pub async fn api_add_candidate(
+    req: HttpRequest,
+    user_session_storage: web::Data<UserSessionStorage>,
+    request: web::Json<AddCandidateRequest>,
+) -> impl Responder {
+    let AddCandidateRequest {
+        session_id,
+        candidate,
+    } = request.into_inner();
+
+    let session = match connected_session(&req, &user_session_storage, session_id) {
+        Ok(s) => s,
+        Err((status, error)) => return HttpResponse::build(status).json(IceError { error }),
+    };
+
+    let CandidateResponse(receiver) = match session.send(AddRemoteCandidate(candidate)).await {
+        Ok(r) => r,
+        Err(e) => {
+            error!(target: "api_ice", "error on sending candidate {}", e);
+            return HttpResponse::build(StatusCode::SERVICE_UNAVAILABLE).json(IceError {
+                error: e.to_string(),
+            });
+        }
+    };
+
+    match receiver.await.map_err(std::io::Error::other) {
+        Ok(Ok(())) => HttpResponse::NoContent().finish(),
+        Err(e) | Ok(Err(e)) => {
+            error!(target: "api_ice", "error on adding candidate {}", e);
+            HttpResponse::build(StatusCode::BAD_REQUEST).json(IceError {
+                error: e.to_string(),
+            })
+        }
+    }
+}
+
+#[get("/candidates/{session_id}")]
+pub async fn api_get_candidates(
+    req: HttpRequest,
+    user_session_storage: web::Data<UserSessionStorage>,
+    path: web::Path<(Uuid,)>,
+) -> impl Responder {
+    let (session_id,) = path.into_inner();
+
+    let session = match connected_session(&req, &user_session_storage, session_id) {
+        Ok(s) => s,
+        Err((status, error)) => return HttpResponse::build(status).json(IceError { error }),
+    };
+
+    match session.send(GetLocalCandidates).await {
+        Ok(LocalCandidates {
+            candidates,
+            complete,
+        }) => HttpResponse::Ok().json(CandidatesResponse {
+            candidates,
+            complete,
+        }),
+        Err(e) => {
+            error!(target: "api_ice", "error on getting candidates {}", e);
+            HttpResponse::build(StatusCode::SERVICE_UNAVAILABLE).json(IceError {
+                error: e.to_string(),
+            })
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AddCandidateRequest {
+    session_id: Uuid,
+    candidate: RTCIceCandidateInit,
+}
+
+#[derive(Serialize)]
+pub struct CandidatesResponse {
+    candidates: Vec<RTCIceCandidateInit>,
+    /// No more candidates will be gathered
+    complete: bool,
+}
+
+/// Servers for the client peer connection, TURN credentials are minted per request.
+#[derive(Serialize)]
+pub struct IceServersResponse {
+    ice_servers: Vec<IceServer>,
+}
+
+#[derive(Serialize)]
+pub struct IceError<E> {
+    error: E,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::test_context::{client_offer, TestContext};
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use serde_json::{json, Value};
+    use std::time::Duration;
+    use uuid::Uuid;
+    use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+    #[actix_web::test]
+    async fn trickled_candidates_are_exchanged() {
+        let ctx = TestContext::offline();
+        let (client, offer) = client_offer(None, true).await;
+        let mut gather_complete = client.gathering_complete_promise().await;
+
+        let (status, body) = ctx
+            .call(
+                test::TestRequest::post()
+                    .uri("/session/create")
+                    .set_json(json!({ "offer": offer, "trickle": true })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let session_id = body["session_id"].as_str().unwrap().to_string();
+        let answer: RTCSessionDescription = serde_json::from_value(body["offer"].clone()).unwrap();
+        client.set_remote_description(answer).await.unwrap();
+
+        let mut candidates = Value::Null;
+        for _ in 0..100 {
+            let (status, body) = ctx
+                .call(test::TestRequest::get().uri(&format!("/session/candidates/{}", session_id)))
+                .await;
+            assert_eq!(status, StatusCode::OK);
+            if body["complete"] == true {
+                candidates = body["candidates"].clone();
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(!candidates
+            .as_array()
+            .expect("candidates weren't gathered")
+            .is_empty());
+        assert!(candidates[0]["candidate"]
+            .as_str()
+            .unwrap()
+            .starts_with("candidate:"));
+
+        let _ = gather_complete.recv().await;
+        let local = client.local_description().await.unwrap();
+        let candidate = local
+            .sdp
+            .lines()
+            .filter_map(|l| l.trim().strip_prefix("a="))
+            .find(|l| l.starts_with("candidate:"))
+            .expect("client has no candidates")
+            .to_string();
+        let status = ctx
+            .call_service(
+                test::TestRequest::post()
+                    .uri("/session/candidate")
+                    .set_json(json!({
+                        "session_id": session_id,
+                        "candidate": { "candidate": candidate, "sdpMid": "0", "sdpMLineIndex": 0 },
+                    })),
+            )
+            .await
+            .status();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, body) = ctx
+            .call(
+                test::TestRequest::post()
+                    .uri("/session/candidate")
+                    .set_json(json!({
+                        "session_id": session_id,
+                        "candidate": { "candidate": "candidate:not a candidate" },
+                    })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].is_string());
+
+        client.close().await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn candidates_of_unknown_session() {
+        let ctx = TestContext::offline();
+
+        let (status, _) = ctx
+            .call(test::TestRequest::get().uri(&format!("/session/candidates/{}", Uuid::new_v4())))
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -1,3 +1,8 @@
 pub mod asr;
+pub mod events;
+pub mod ice;
 pub mod jwt;
 pub mod session;
+#[cfg(test)]
+pub mod test_context;
+pub mod whip;
@@ -1,5 +1,16 @@
+use crate::asr::client::SpeechModel;
+use crate::asr::launcher::AsrLauncher;
+use crate::audio::transcode::Transcoder;
+use crate::audio::AudioFormat;
+use crate::events::broker::EventBroker;
 use crate::garbage::collector::GarbageCollector;
-use crate::webrtc::{create_session, get_audio_path, OfferRequest, OfferResponse};
+use crate::webrtc::ice::IceConfig;
+use crate::webrtc::quota::{QuotaExceeded, StorageQuota};
+use crate::webrtc::segmenter::SegmentConfig;
+use crate::webrtc::{
+    create_session, find_audio_path, LiveRecognition, OfferRequest, OfferResponse, PauseRecording,
+    ResumeRecording, Session, SessionOptions,
+};
 use crate::{UserId, UserSessionStorage};
 use actix::Addr;
 use actix_files::NamedFile;
@@ -8,6 +19,7 @@ use actix_web::{get, post, web, HttpMessage, HttpRequest, HttpResponse, Responde
 use log::error;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 use uuid::Uuid;
 use webrtc::api::API;
@@ -18,6 +30,7 @@ pub async fn api_get_audio(
     req: HttpRequest,
     user_session_storage: web::Data<UserSessionStorage>,
     path: web::Path<(Uuid,)>,
+    query: web::Query<TrackQuery>,
     config: web::Data<SessionConfig>,
 ) -> impl Responder {
     let user_id = match req.extensions().get::<UserId>() {
@@ -41,9 +54,133 @@ pub async fn api_get_audio(
         ));
     }
 
-    NamedFile::open_async(get_audio_path(session_id, config.dir.clone())).await
+    match find_audio_path(session_id, query.track, config.dir.clone()) {
+        Some((path, _)) => NamedFile::open_async(path).await,
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "track wasn't recorded",
+        )),
+    }
 }
 
+#[get("/tracks/{session_id}")]
+pub async fn api_get_tracks(
+    req: HttpRequest,
+    user_session_storage: web::Data<UserSessionStorage>,
+    path: web::Path<(Uuid,)>,
+    config: web::Data<SessionConfig>,
+) -> impl Responder {
+    let user_id = match req.extensions().get::<UserId>() {
+        None => {
+            return HttpResponse::build(StatusCode::UNAUTHORIZED).json(SessionErrorResponse {
+                error: "authorization is failed",
+            });
+        }
+        Some(&uid) => uid,
+    };
+
+    let (session_id,) = path.into_inner();
+
+    let session_storage = user_session_storage.entry(user_id).or_default().clone();
+
+    match session_storage.get(&session_id) {
+        None => {
+            return HttpResponse::build(StatusCode::NOT_FOUND).json(SessionErrorResponse {
+                error: "webrtc session wasn't created",
+            });
+        }
+        Some(s) if s.connected() => {
+            return HttpResponse::build(StatusCode::CONFLICT).json(SessionErrorResponse {
+                error: "session is writing",
+            });
+        }
+        _ => {}
+    }
+
+    // tracks are numbered in the order they're received
+    let tracks = (0..)
+        .map_while(|track| {
+            find_audio_path(session_id, track, config.dir.clone())
+                .map(|(_, format)| TrackResponse { track, format })
+        })
+        .collect();
+
+    HttpResponse::Ok().json(TracksResponse { tracks })
+}
+
+#[post("/pause")]
+pub async fn api_pause_session(
+    req: HttpRequest,
+    user_session_storage: web::Data<UserSessionStorage>,
+    request: web::Json<SessionControlRequest>,
+) -> impl Responder {
+    control_session(&req, &user_session_storage, request.session_id, true).await
+}
+
+#[post("/resume")]
+pub async fn api_resume_session(
+    req: HttpRequest,
+    user_session_storage: web::Data<UserSessionStorage>,
+    request: web::Json<SessionControlRequest>,
+) -> impl Responder {
+    control_session(&req, &user_session_storage, request.session_id, false).await
+}
+
+/// Pauses or resumes the recording of the connected session.
+async fn control_session(
+    req: &HttpRequest,
+    user_session_storage: &UserSessionStorage,
+    session_id: Uuid,
+    pause: bool,
+) -> HttpResponse {
+    let session = match connected_session(req, user_session_storage, session_id) {
+        Ok(s) => s,
+        Err((status, error)) => {
+            return HttpResponse::build(status).json(SessionErrorResponse { error });
+        }
+    };
+
+    let result = if pause {
+        session.send(PauseRecording).await
+    } else {
+        session.send(ResumeRecording).await
+    };
+
+    match result {
+        Ok(()) => HttpResponse::Ok().json(SessionControlResponse {
+            session_id,
+            paused: pause,
+        }),
+        Err(e) => {
+            error!(target: "api_session", "error on controlling session {}", e);
+            HttpResponse::build(StatusCode::SERVICE_UNAVAILABLE).json(SessionErrorResponse {
+                error: e.to_string(),
+            })
+        }
+    }
+}
+
+/// Finds the session of the authorized user, it must be still connected.
+pub(crate) fn connected_session(
+    req: &HttpRequest,
+    user_session_storage: &UserSessionStorage,
+    session_id: Uuid,
+) -> Result<Addr<Session>, (StatusCode, &'static str)> {
+    let Some(&user_id) = req.extensions().get::<UserId>() else {
+        return Err((StatusCode::UNAUTHORIZED, "authorization is failed"));
+    };
+
+    match user_session_storage
+        .get(&user_id)
+        .and_then(|s| s.get(&session_id).map(|s| s.clone()))
+    {
+        Some(s) if s.connected() => Ok(s),
+        Some(_) => Err((StatusCode::CONFLICT, "session is closed")),
+        None => Err((StatusCode::NOT_FOUND, "webrtc session wasn't created")),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 #[post("/create")]
 pub async fn api_create_session(
     req: HttpRequest,
@@ -51,6 +188,8 @@ pub async fn api_create_session(
     user_session_storage: web::Data<UserSessionStorage>,
     config: web::Data<SessionConfig>,
     garbage_collector: web::Data<Addr<GarbageCollector>>,
+    events: web::Data<Addr<EventBroker>>,
+    asr_launcher: web::Data<AsrLauncher>,
     offer_request: web::Json<CreateSessionRequest>,
 ) -> impl Responder {
     let user_id = match req.extensions().get::<UserId>() {
@@ -62,59 +201,188 @@ pub async fn api_create_session(
         Some(&uid) => uid,
     };
 
-    let (session_id, session) = match create_session(
+    let CreateSessionRequest {
+        offer,
+        live,
+        format,
+        mono,
+        trickle,
+    } = offer_request.into_inner();
+
+    let live = live.map(|l| LiveRecognition {
+        recognizer: asr_launcher.recognizer.clone(),
+        speech_model: l.speech,
+        deadline: asr_launcher.config.deadline,
+    });
+
+    let (session_id, session) = match start_session(
         user_id,
-        api.as_ref(),
-        user_session_storage.entry(user_id).or_default().clone(),
+        &api,
+        &user_session_storage,
+        &config,
         garbage_collector.into_inner(),
-        SessionConfig::clone(&config),
+        events.into_inner(),
+        SessionOptions {
+            live,
+            format,
+            mono,
+            asr: Some(AsrLauncher::clone(&asr_launcher)),
+        },
     )
     .await
     {
         Ok(r) => r,
-        Err(e) => {
-            error!(target: "api_session", "error on creating session {}", e);
-            return HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).json(
-                SessionErrorResponse {
-                    error: e.to_string(),
-                },
-            );
+        Err((status, error)) => {
+            return HttpResponse::build(status).json(SessionErrorResponse { error });
         }
     };
 
-    let CreateSessionRequest { offer } = offer_request.into_inner();
+    match answer_offer(&session, offer, trickle).await {
+        Ok(offer) => HttpResponse::Ok().json(SessionCreatedResponse { session_id, offer }),
+        Err((status, error)) => HttpResponse::build(status).json(SessionErrorResponse { error }),
+    }
+}
 
-    let OfferResponse(receiver) = match session.send(OfferRequest(offer)).await {
-        Ok(r) => r,
-        Err(e) => {
-            error!(target: "api_session", "error on sending offer {}", e);
-            return HttpResponse::build(StatusCode::SERVICE_UNAVAILABLE).json(
-                SessionErrorResponse {
-                    error: e.to_string(),
-                },
-            );
+/// Creates the session of the user when the storage quota isn't exceeded yet.
+pub(crate) async fn start_session(
+    user_id: UserId,
+    api: &API,
+    user_session_storage: &UserSessionStorage,
+    config: &SessionConfig,
+    garbage_collector: Arc<Addr<GarbageCollector>>,
+    events: Arc<Addr<EventBroker>>,
+    options: SessionOptions,
+) -> Result<(Uuid, Addr<Session>), (StatusCode, String)> {
+    if let Err(exceeded) = config.quota.check(user_id) {
+        let error = match exceeded {
+            QuotaExceeded::User => "user storage quota is exceeded",
+            _ => "storage quota is exceeded",
+        };
+        return Err((StatusCode::INSUFFICIENT_STORAGE, error.to_string()));
+    }
+
+    create_session(
+        user_id,
+        api,
+        user_session_storage.entry(user_id).or_default().clone(),
+        garbage_collector,
+        events,
+        config.clone(),
+        options,
+    )
+    .await
+    .map_err(|e| {
+        error!(target: "api_session", "error on creating session {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })
+}
+
+#[post("/renegotiate")]
+pub async fn api_renegotiate_session(
+    req: HttpRequest,
+    user_session_storage: web::Data<UserSessionStorage>,
+    request: web::Json<RenegotiateSessionRequest>,
+) -> impl Responder {
+    let RenegotiateSessionRequest {
+        session_id,
+        offer,
+        trickle,
+    } = request.into_inner();
+
+    let session = match connected_session(&req, &user_session_storage, session_id) {
+        Ok(s) => s,
+        Err((status, error)) => {
+            return HttpResponse::build(status).json(SessionErrorResponse { error });
         }
     };
 
-    let offer = match receiver
-        .await
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
-    {
-        Ok(Ok(r)) => r,
+    match answer_offer(&session, offer, trickle).await {
+        Ok(offer) => HttpResponse::Ok().json(SessionCreatedResponse { session_id, offer }),
+        Err((status, error)) => HttpResponse::build(status).json(SessionErrorResponse { error }),
+    }
+}
+
+pub(crate) async fn answer_offer(
+    session: &Addr<Session>,
+    offer: RTCSessionDescription,
+    trickle: bool,
+) -> Result<RTCSessionDescription, (StatusCode, String)> {
+    let OfferResponse(receiver) =
+        session
+            .send(OfferRequest(offer, trickle))
+            .await
+            .map_err(|e| {
+                error!(target: "api_session", "error on sending offer {}", e);
+                (StatusCode::SERVICE_UNAVAILABLE, e.to_string())
+            })?;
+
+    match receiver.await.map_err(std::io::Error::other) {
+        Ok(Ok(answer)) => Ok(answer),
         Err(e) | Ok(Err(e)) => {
             error!(target: "api_session", "error on accepting offer {}", e);
-            return HttpResponse::build(StatusCode::NOT_ACCEPTABLE).json(SessionErrorResponse {
-                error: e.to_string(),
-            });
+            Err((StatusCode::NOT_ACCEPTABLE, e.to_string()))
         }
-    };
-
-    HttpResponse::build(StatusCode::OK).json(SessionCreatedResponse { session_id, offer })
+    }
 }
 
 #[derive(Deserialize)]
 pub struct CreateSessionRequest {
     offer: RTCSessionDescription,
+    /// Speech is recognized while the session is writing, partial texts are sent as events
+    #[serde(default)]
+    live: Option<LiveRecognitionRequest>,
+    /// Format of the stored recording, by default it depends on the negotiated codec
+    #[serde(default)]
+    format: Option<AudioFormat>,
+    /// Stereo recording is downmixed to mono
+    #[serde(default)]
+    mono: bool,
+    /// Answer is returned before the server candidates are gathered, they're fetched separately
+    #[serde(default)]
+    trickle: bool,
+}
+
+/// New offer of the connected session, e.g. with ICE restart after the network is changed.
+#[derive(Deserialize)]
+pub struct RenegotiateSessionRequest {
+    session_id: Uuid,
+    offer: RTCSessionDescription,
+    #[serde(default)]
+    trickle: bool,
+}
+
+/// Selects the recorded session track, the first one by default.
+#[derive(Deserialize)]
+pub struct TrackQuery {
+    #[serde(default)]
+    pub track: usize,
+}
+
+#[derive(Serialize)]
+pub struct TracksResponse {
+    tracks: Vec<TrackResponse>,
+}
+
+#[derive(Serialize)]
+pub struct TrackResponse {
+    track: usize,
+    format: AudioFormat,
+}
+
+#[derive(Deserialize)]
+pub struct LiveRecognitionRequest {
+    speech: SpeechModel,
+}
+
+#[derive(Deserialize)]
+pub struct SessionControlRequest {
+    session_id: Uuid,
+}
+
+#[derive(Serialize)]
+pub struct SessionControlResponse {
+    session_id: Uuid,
+    paused: bool,
 }
 
 #[derive(Serialize)]
@@ -133,4 +401,183 @@ pub struct SessionConfig {
     pub dir: PathBuf,
     pub total_timeout: Duration,
     pub timeout: Duration,
+    /// Disconnected session is closed when ICE isn't restarted during this period
+    pub ice_grace_period: Duration,
+    pub segment: SegmentConfig,
+    pub transcoder: Transcoder,
+    /// How many packets are buffered after a gap before it's considered lost
+    pub jitter_buffer: usize,
+    pub quota: Arc<StorageQuota>,
+    pub ice: Arc<IceConfig>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::test_context::{
+        client_offer, close_session, renegotiation_offer, TestContext, USER_ID,
+    };
+    use crate::webrtc::{GetLocalCandidates, LocalCandidates, Session};
+    use actix::Addr;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use serde_json::{json, Value};
+    use std::time::Duration;
+    use uuid::Uuid;
+    use webrtc::peer_connection::offer_answer_options::RTCOfferOptions;
+    use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+    use webrtc::peer_connection::RTCPeerConnection;
+
+    async fn accept_answer(client: &RTCPeerConnection, body: &Value) {
+        let answer: RTCSessionDescription = serde_json::from_value(body["offer"].clone()).unwrap();
+        client.set_remote_description(answer).await.unwrap();
+    }
+
+    /// Waits until the server gathers its candidates.
+    async fn gathered_candidates(session: &Addr<Session>) -> LocalCandidates {
+        for _ in 0..100 {
+            let candidates = session.send(GetLocalCandidates).await.unwrap();
+            if candidates.complete {
+                return candidates;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("candidates weren't gathered");
+    }
+
+    #[actix_web::test]
+    async fn renegotiation_regathers_candidates_on_ice_restart_only() {
+        let ctx = TestContext::offline();
+        let (client, offer) = client_offer(None, false).await;
+
+        let (status, body) = ctx
+            .call(
+                test::TestRequest::post()
+                    .uri("/session/create")
+                    .set_json(json!({ "offer": offer })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        accept_answer(&client, &body).await;
+        let session_id = body["session_id"]
+            .as_str()
+            .unwrap()
+            .parse::<Uuid>()
+            .unwrap();
+        let session = ctx
+            .user_session_storage
+            .get(&USER_ID)
+            .and_then(|s| s.get(&session_id).map(|s| s.clone()))
+            .expect("session isn't stored");
+        let gathered = gathered_candidates(&session).await;
+        assert!(!gathered.candidates.is_empty());
+
+        // the same credentials don't gather candidates again, the trickled answer keeps them
+        let offer = renegotiation_offer(&client, None, true).await;
+        let (status, body) = ctx
+            .call(
+                test::TestRequest::post()
+                    .uri("/session/renegotiate")
+                    .set_json(json!({ "session_id": session_id, "offer": offer, "trickle": true })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        accept_answer(&client, &body).await;
+        let kept = session.send(GetLocalCandidates).await.unwrap();
+        assert!(kept.complete);
+        assert_eq!(kept.candidates.len(), gathered.candidates.len());
+
+        let offer = renegotiation_offer(
+            &client,
+            Some(RTCOfferOptions {
+                ice_restart: true,
+                ..Default::default()
+            }),
+            true,
+        )
+        .await;
+        let (status, body) = ctx
+            .call(
+                test::TestRequest::post()
+                    .uri("/session/renegotiate")
+                    .set_json(json!({ "session_id": session_id, "offer": offer, "trickle": true })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        accept_answer(&client, &body).await;
+        let restarted = gathered_candidates(&session).await;
+        assert!(!restarted.candidates.is_empty());
+        assert_ne!(
+            restarted.candidates[0].candidate,
+            gathered.candidates[0].candidate
+        );
+
+        client.close().await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn renegotiation_of_unknown_session() {
+        let ctx = TestContext::offline();
+        let (client, offer) = client_offer(None, false).await;
+
+        let (status, body) = ctx
+            .call(
+                test::TestRequest::post()
+                    .uri("/session/renegotiate")
+                    .set_json(json!({ "session_id": Uuid::new_v4(), "offer": offer })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "webrtc session wasn't created");
+
+        client.close().await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[actix_web::test]
+    async fn recording_is_stored_in_the_chosen_format() {
+        use crate::audio::transcode::Transcoder;
+        use std::os::unix::fs::PermissionsExt;
+
+        let ctx = TestContext::offline();
+        // stub writes the output file, it's the last argument
+        let ffmpeg = ctx.config.dir.join("ffmpeg");
+        std::fs::write(
+            &ffmpeg,
+            "#!/bin/sh\nfor output; do :; done\nprintf fLaC > \"$output\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&ffmpeg, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let ctx = ctx.with_transcoder(Transcoder::new(ffmpeg));
+        let (client, offer) = client_offer(None, false).await;
+
+        let (status, body) = ctx
+            .call(
+                test::TestRequest::post()
+                    .uri("/session/create")
+                    .set_json(json!({ "offer": offer, "format": "flac" })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let session_id = body["session_id"]
+            .as_str()
+            .unwrap()
+            .parse::<Uuid>()
+            .unwrap();
+        let session = ctx
+            .user_session_storage
+            .get(&USER_ID)
+            .and_then(|s| s.get(&session_id).map(|s| s.clone()))
+            .expect("session isn't stored");
+        close_session(&session).await;
+
+        let resp = ctx
+            .call_service(test::TestRequest::get().uri(&format!("/session/listen/{}", session_id)))
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, "fLaC");
+        assert!(ctx.config.dir.join(format!("{}.flac", session_id)).exists());
+        assert!(!ctx.config.dir.join(format!("{}.ogg", session_id)).exists());
+
+        client.close().await.unwrap();
+    }
 }
@@ -0,0 +1,279 @@
+//! Application state of the api handlers for tests without network.
+//! Recordings of each context are written to its own directory, it's removed with the context.
+use crate::api::asr::{api_get_asr_state, api_text_to_speech};
+use crate::api::events::api_session_events;
+use crate::api::ice::{api_add_candidate, api_get_candidates};
+use crate::api::session::{api_create_session, api_get_audio, api_renegotiate_session};
+use crate::api::whip::{api_whip_create, api_whip_delete};
+use crate::asr::backoff::BackoffConfig;
+use crate::asr::client::VkApi;
+use crate::asr::launcher::AsrLauncher;
+use crate::asr::recognizer::SpeechRecognizer;
+use crate::asr::retry::{CircuitBreaker, RetryPolicy};
+use crate::asr::vk::VkRecognizer;
+use crate::asr::AsrConfig;
+use crate::audio::transcode::Transcoder;
+use crate::events::broker::EventBroker;
+use crate::garbage::collector::GarbageCollector;
+use crate::webhook::dispatcher::{WebhookConfig, WebhookDispatcher};
+use crate::webrtc::ice::IceConfig;
+use crate::webrtc::network::NetworkConfig;
+use crate::webrtc::quota::{QuotaLimits, StorageQuota};
+use crate::webrtc::segmenter::SegmentConfig;
+use crate::webrtc::{
+    create_api, create_session, CloseReason, CloseSession, Closed, PortRange, Session,
+    SessionOptions,
+};
+use crate::{SessionConfig, UserAsrProcessorStorage, UserId, UserSessionStorage};
+use actix::Addr;
+use actix_web::http::StatusCode;
+use actix_web::web::scope;
+use actix_web::{test, web, App, HttpMessage};
+use serde_json::Value;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+use uuid::Uuid;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::offer_answer_options::RTCOfferOptions;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+
+pub const USER_ID: UserId = UserId(1);
+
+pub struct TestContext {
+    pub user_session_storage: web::Data<UserSessionStorage>,
+    pub user_asr_processor_storage: web::Data<UserAsrProcessorStorage>,
+    pub config: web::Data<SessionConfig>,
+    pub garbage_collector: web::Data<Addr<GarbageCollector>>,
+    pub asr_launcher: web::Data<AsrLauncher>,
+    pub webhook_config: web::Data<WebhookConfig>,
+    pub events: web::Data<Addr<EventBroker>>,
+    dir: PathBuf,
+}
+
+impl TestContext {
+    pub fn new(vk_api_url: Url, deadline: Duration) -> Self {
+        let dir = std::env::temp_dir().join(format!("wacr-test-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).expect("fail to create test dir");
+
+        let user_session_storage = web::Data::new(UserSessionStorage::new());
+        let user_asr_processor_storage = web::Data::new(UserAsrProcessorStorage::new());
+        let config = web::Data::new(SessionConfig {
+            dir: dir.clone(),
+            timeout: Duration::from_secs(10),
+            total_timeout: Duration::from_secs(100),
+            ice_grace_period: Duration::from_secs(10),
+            segment: SegmentConfig {
+                max_duration: Duration::from_secs(5),
+                silence: Duration::from_millis(600),
+                threshold: -45.0,
+            },
+            transcoder: Transcoder::new("ffmpeg".into()),
+            jitter_buffer: 5,
+            quota: Arc::new(StorageQuota::new(QuotaLimits::default(), dir.clone())),
+            ice: Arc::new(IceConfig::default()),
+        });
+        let events = web::Data::new(EventBroker::new());
+        let garbage_collector = web::Data::new(GarbageCollector::new(
+            user_session_storage.clone().into_inner(),
+            user_asr_processor_storage.clone().into_inner(),
+            events.clone().into_inner(),
+            config.quota.clone(),
+            config.dir.clone(),
+            3600,
+        ));
+        let backoff = BackoffConfig {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(100),
+            multiplier: 2.0,
+        };
+        let recognizer: Arc<dyn SpeechRecognizer> = Arc::new(VkRecognizer::new(
+            VkApi::new("token".to_string(), vk_api_url),
+            backoff,
+            RetryPolicy {
+                attempts: 3,
+                backoff,
+                timeout: Duration::from_secs(5),
+            },
+            CircuitBreaker::new(5, Duration::from_secs(30)),
+        ));
+
+        let webhook_config = WebhookConfig {
+            url: None,
+            secret: None,
+            allowed_hosts: None,
+            dir: dir.join("webhooks"),
+            attempts: 1,
+            backoff,
+        };
+
+        let asr_launcher = web::Data::new(AsrLauncher {
+            storage: user_asr_processor_storage.clone().into_inner(),
+            recognizer,
+            config: AsrConfig {
+                deadline,
+                trimmer: None,
+            },
+            dir: config.dir.clone(),
+            callback: None,
+            garbage_collector: garbage_collector.clone().into_inner(),
+            webhook_dispatcher: Arc::new(WebhookDispatcher::new(webhook_config.clone())),
+            events: events.clone().into_inner(),
+        });
+
+        Self {
+            user_session_storage,
+            user_asr_processor_storage,
+            config,
+            garbage_collector,
+            asr_launcher,
+            webhook_config: web::Data::new(webhook_config),
+            events,
+            dir,
+        }
+    }
+
+    /// Context of the tests which don't reach VK API.
+    pub fn offline() -> Self {
+        Self::new(
+            Url::parse("http://127.0.0.1:1").unwrap(),
+            Duration::from_secs(10),
+        )
+    }
+
+    /// Recordings are converted by the stub of ffmpeg.
+    pub fn with_transcoder(mut self, transcoder: Transcoder) -> Self {
+        self.config = web::Data::new(SessionConfig {
+            transcoder,
+            ..SessionConfig::clone(&self.config)
+        });
+        self
+    }
+
+    pub async fn create_session(&self) -> (Uuid, Addr<Session>) {
+        let api = create_api(PortRange(0, 0), NetworkConfig::default()).unwrap();
+        create_session(
+            USER_ID,
+            &api,
+            self.user_session_storage
+                .entry(USER_ID)
+                .or_default()
+                .clone(),
+            self.garbage_collector.clone().into_inner(),
+            self.events.clone().into_inner(),
+            SessionConfig::clone(&self.config),
+            SessionOptions::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Session closed for recognition without packets, its recording is empty.
+    pub async fn create_closed_session(&self) -> Uuid {
+        let (session_id, session) = self.create_session().await;
+
+        let Closed(rx) = session
+            .send(CloseSession(CloseReason::Recognition))
+            .await
+            .unwrap();
+        rx.await.unwrap();
+
+        session_id
+    }
+
+    pub async fn call(&self, req: test::TestRequest) -> (StatusCode, Value) {
+        let resp = self.call_service(req).await;
+        let status = resp.status();
+
+        (status, test::read_body_json(resp).await)
+    }
+
+    /// Calls the handlers of the `/session` scope authorized as `USER_ID`.
+    pub async fn call_service(&self, req: test::TestRequest) -> actix_web::dev::ServiceResponse {
+        let app = test::init_service(
+            App::new()
+                .app_data(self.user_session_storage.clone())
+                .app_data(self.user_asr_processor_storage.clone())
+                .app_data(self.asr_launcher.clone())
+                .app_data(self.webhook_config.clone())
+                .app_data(self.events.clone())
+                .app_data(self.config.clone())
+                .app_data(self.garbage_collector.clone())
+                .app_data(web::Data::new(
+                    create_api(PortRange(0, 0), NetworkConfig::default()).unwrap(),
+                ))
+                .service(
+                    scope("/session")
+                        .service(api_text_to_speech)
+                        .service(api_get_asr_state)
+                        .service(api_session_events)
+                        .service(api_create_session)
+                        .service(api_renegotiate_session)
+                        .service(api_get_audio)
+                        .service(api_add_candidate)
+                        .service(api_get_candidates)
+                        .service(api_whip_create)
+                        .service(api_whip_delete),
+                ),
+        )
+        .await;
+
+        let req = req.to_request();
+        req.extensions_mut().insert(USER_ID);
+
+        test::call_service(&app, req).await
+    }
+}
+
+impl Drop for TestContext {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Peer connection of the client with one audio transceiver and its offer,
+/// candidates are gathered unless the offer is trickled.
+pub async fn client_offer(
+    options: Option<RTCOfferOptions>,
+    trickle: bool,
+) -> (Arc<RTCPeerConnection>, RTCSessionDescription) {
+    let api = create_api(PortRange(0, 0), NetworkConfig::default()).unwrap();
+    let client = Arc::new(
+        api.new_peer_connection(RTCConfiguration::default())
+            .await
+            .unwrap(),
+    );
+    client
+        .add_transceiver_from_kind(RTPCodecType::Audio, &[])
+        .await
+        .unwrap();
+    let offer = renegotiation_offer(&client, options, trickle).await;
+    (client, offer)
+}
+
+/// Next offer of the connected client.
+pub async fn renegotiation_offer(
+    client: &RTCPeerConnection,
+    options: Option<RTCOfferOptions>,
+    trickle: bool,
+) -> RTCSessionDescription {
+    let offer = client.create_offer(options).await.unwrap();
+    let mut gather_complete = client.gathering_complete_promise().await;
+    client.set_local_description(offer).await.unwrap();
+    if !trickle {
+        let _ = gather_complete.recv().await;
+    }
+    client.local_description().await.unwrap()
+}
+
+/// Waits until the recording of the session is finalized.
+pub async fn close_session(session: &Addr<Session>) {
+    let Closed(rx) = session
+        .send(CloseSession(CloseReason::Stopped))
+        .await
+        .unwrap();
+    rx.await.unwrap();
+}
@@ -0,0 +1,293 @@
+use crate::api::session::{answer_offer, connected_session, start_session, SessionConfig};
+use crate::asr::client::SpeechModel;
+use crate::asr::launcher::AsrLauncher;
+use crate::audio::AudioFormat;
+use crate::events::broker::EventBroker;
+use crate::garbage::collector::GarbageCollector;
+use crate::webrtc::{CloseReason, CloseSession, LiveRecognition, SessionOptions};
+use crate::{UserId, UserSessionStorage};
+use actix::Addr;
+use actix_web::http::{header, StatusCode};
+use actix_web::{delete, post, web, HttpMessage, HttpRequest, HttpResponse, Resource, Responder};
+use log::error;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use webrtc::api::API;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+const SDP_CONTENT_TYPE: &str = "application/sdp";
+
+/// WebRTC-HTTP Ingestion Protocol (RFC 9725), the session is created by the plain SDP offer.
+#[allow(clippy::too_many_arguments)]
+#[post("/whip")]
+pub async fn api_whip_create(
+    req: HttpRequest,
+    api: web::Data<API>,
+    user_session_storage: web::Data<UserSessionStorage>,
+    config: web::Data<SessionConfig>,
+    garbage_collector: web::Data<Addr<GarbageCollector>>,
+    events: web::Data<Addr<EventBroker>>,
+    asr_launcher: web::Data<AsrLauncher>,
+    query: web::Query<WhipQuery>,
+    sdp: String,
+) -> impl Responder {
+    let user_id = match req.extensions().get::<UserId>() {
+        None => {
+            return HttpResponse::build(StatusCode::UNAUTHORIZED).json(WhipError {
+                error: "authorization is failed",
+            });
+        }
+        Some(&uid) => uid,
+    };
+
+    if req.content_type() != SDP_CONTENT_TYPE {
+        return HttpResponse::build(StatusCode::UNSUPPORTED_MEDIA_TYPE).json(WhipError {
+            error: format!("content type must be {}", SDP_CONTENT_TYPE),
+        });
+    }
+
+    let offer = match RTCSessionDescription::offer(sdp) {
+        Ok(o) => o,
+        Err(e) => {
+            return HttpResponse::build(StatusCode::BAD_REQUEST).json(WhipError {
+                error: e.to_string(),
+            });
+        }
+    };
+
+    let WhipQuery {
+        speech,
+        format,
+        mono,
+    } = query.into_inner();
+
+    let live = speech.map(|speech_model| LiveRecognition {
+        recognizer: asr_launcher.recognizer.clone(),
+        speech_model,
+        deadline: asr_launcher.config.deadline,
+    });
+
+    let (session_id, session) = match start_session(
+        user_id,
+        &api,
+        &user_session_storage,
+        &config,
+        garbage_collector.into_inner(),
+        events.into_inner(),
+        SessionOptions {
+            live,
+            format,
+            mono,
+            asr: Some(AsrLauncher::clone(&asr_launcher)),
+        },
+    )
+    .await
+    {
+        Ok(r) => r,
+        Err((status, error)) => return HttpResponse::build(status).json(WhipError { error }),
+    };
+
+    // candidates can't be trickled, the answer contains all of them
+    match answer_offer(&session, offer, false).await {
+        Ok(answer) => HttpResponse::Created()
+            .insert_header((
+                header::LOCATION,
+                format!("{}/{}", req.path().trim_end_matches('/'), session_id),
+            ))
+            .content_type(SDP_CONTENT_TYPE)
+            .body(answer.sdp),
+        Err((status, error)) => {
+            session.do_send(CloseSession(CloseReason::Stopped));
+            HttpResponse::build(status).json(WhipError { error })
+        }
+    }
+}
+
+/// Ends the session created by WHIP, its recording is kept like of any other session.
+#[delete("/whip/{session_id}")]
+pub async fn api_whip_delete(
+    req: HttpRequest,
+    user_session_storage: web::Data<UserSessionStorage>,
+    path: web::Path<(Uuid,)>,
+) -> impl Responder {
+    let (session_id,) = path.into_inner();
+
+    let session = match connected_session(&req, &user_session_storage, session_id) {
+        Ok(s) => s,
+        Err((status, error)) => {
+            return HttpResponse::build(status).json(WhipError { error });
+        }
+    };
+
+    // recording is finalized in background, the resource is gone already
+    match session.send(CloseSession(CloseReason::Stopped)).await {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            error!(target: "api_whip", "error on closing session {}", e);
+            HttpResponse::build(StatusCode::SERVICE_UNAVAILABLE).json(WhipError {
+                error: e.to_string(),
+            })
+        }
+    }
+}
+
+/// Requests failed the guard of the `/session` scope don't match it at all,
+/// WHIP clients get `401 Unauthorized` by this resource instead of `404 Not Found`.
+pub fn whip_unauthorized(scope: &str) -> Resource {
+    web::resource([
+        format!("{}/whip", scope),
+        format!("{}/whip/{{session_id}}", scope),
+    ])
+    .to(|| async {
+        HttpResponse::build(StatusCode::UNAUTHORIZED).json(WhipError {
+            error: "authorization is failed",
+        })
+    })
+}
+
+/// Options of `CreateSessionRequest` passed in the query of the WHIP endpoint.
+#[derive(Deserialize)]
+pub struct WhipQuery {
+    /// Speech is recognized while the session is writing
+    #[serde(default)]
+    speech: Option<SpeechModel>,
+    #[serde(default)]
+    format: Option<AudioFormat>,
+    #[serde(default)]
+    mono: bool,
+}
+
+#[derive(Serialize)]
+pub struct WhipError<E> {
+    error: E,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::test_context::{client_offer, TestContext, USER_ID};
+    use crate::api::whip::{api_whip_create, api_whip_delete, whip_unauthorized};
+    use actix_web::http::{header, StatusCode};
+    use actix_web::web::scope;
+    use actix_web::{test, App};
+    use serde_json::json;
+    use std::time::Duration;
+    use uuid::Uuid;
+    use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
+    use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+    #[actix_web::test]
+    async fn whip_session_is_created_and_deleted() {
+        let ctx = TestContext::offline();
+        let (client, offer) = client_offer(None, false).await;
+
+        let resp = ctx
+            .call_service(
+                test::TestRequest::post()
+                    .uri("/session/whip")
+                    .insert_header((header::CONTENT_TYPE, "application/sdp"))
+                    .set_payload(offer.sdp),
+            )
+            .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/sdp"
+        );
+        let location = resp
+            .headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let answer = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        let answer = RTCSessionDescription::answer(answer).unwrap();
+        assert_eq!(answer.sdp_type, RTCSdpType::Answer);
+        client.set_remote_description(answer).await.unwrap();
+
+        let session_id = location
+            .strip_prefix("/session/whip/")
+            .and_then(|id| id.parse::<Uuid>().ok())
+            .expect("location isn't the whip session resource");
+        let session = ctx
+            .user_session_storage
+            .get(&USER_ID)
+            .and_then(|s| s.get(&session_id).map(|s| s.clone()))
+            .expect("session isn't stored");
+        assert!(session.connected());
+
+        let resp = ctx
+            .call_service(test::TestRequest::delete().uri(&location))
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        for _ in 0..100 {
+            if !session.connected() {
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(!session.connected());
+
+        client.close().await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn whip_rejects_invalid_requests() {
+        let ctx = TestContext::offline();
+
+        let (status, _) = ctx
+            .call(
+                test::TestRequest::post()
+                    .uri("/session/whip")
+                    .set_json(json!({ "sdp": "v=0" })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let (status, _) = ctx
+            .call(
+                test::TestRequest::post()
+                    .uri("/session/whip")
+                    .insert_header(("content-type", "application/sdp"))
+                    .set_payload("not an offer"),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = ctx
+            .call(test::TestRequest::delete().uri(&format!("/session/whip/{}", Uuid::new_v4())))
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let session_id = ctx.create_closed_session().await;
+        let (status, body) = ctx
+            .call(test::TestRequest::delete().uri(&format!("/session/whip/{}", session_id)))
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error"], "session is closed");
+    }
+
+    #[actix_web::test]
+    async fn whip_without_token_is_unauthorized() {
+        let app = test::init_service(
+            App::new()
+                .service(
+                    scope("/session")
+                        .guard(actix_web::guard::fn_guard(|_| false))
+                        .service(api_whip_create)
+                        .service(api_whip_delete),
+                )
+                .service(whip_unauthorized("/session")),
+        )
+        .await;
+
+        for req in [
+            test::TestRequest::post().uri("/session/whip"),
+            test::TestRequest::delete().uri(&format!("/session/whip/{}", Uuid::new_v4())),
+        ] {
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+}
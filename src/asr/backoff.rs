@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+/// Exponentially growing delays: `initial`, `initial * multiplier`, ... capped by `max`.
+#[derive(Debug, Clone, Copy)]
+pub struct BackoffConfig {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+}
+
+impl BackoffConfig {
+    pub fn delays(&self) -> Backoff {
+        Backoff {
+            next: self.initial.min(self.max),
+            config: *self,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    next: Duration,
+    config: BackoffConfig,
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let current = self.next;
+        self.next = self
+            .next
+            .mul_f64(self.config.multiplier.max(1.0))
+            .min(self.config.max);
+        Some(current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asr::backoff::BackoffConfig;
+    use std::time::Duration;
+
+    #[test]
+    fn exponential_delays_are_capped() {
+        let delays = BackoffConfig {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(5),
+            multiplier: 2.0,
+        }
+        .delays()
+        .take(5)
+        .collect::<Vec<_>>();
+
+        assert_eq!(delays, [1, 2, 4, 5, 5].map(Duration::from_secs).to_vec());
+    }
+
+    #[test]
+    fn multiplier_below_one_keeps_interval() {
+        let delays = BackoffConfig {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(5),
+            multiplier: 0.5,
+        }
+        .delays()
+        .take(3)
+        .collect::<Vec<_>>();
+
+        assert_eq!(delays, [1, 1, 1].map(Duration::from_secs).to_vec());
+    }
+}
@@ -1,17 +1,37 @@
+use hyper::body::Buf;
+use hyper::client::HttpConnector;
+use hyper::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Body, Client, Method, Request};
+use hyper_multipart_rfc7578::client::multipart::{Body as MultipartBody, Form};
+use hyper_rustls::HttpsConnector;
 use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
 use url::Url;
 use uuid::Uuid;
-use vkclient::{Version, VkApi as VkApiInner, VkApiBuilder, VkApiError, VkApiWrapper};
+use vkclient::{Version, VkApiWrapper};
 
+/// VK API client with configurable endpoint.
+/// Speaks both http and https, so it can be pointed to a mock server.
 #[derive(Clone)]
 pub struct VkApi {
-    inner: VkApiInner,
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    service_token: String,
+    base_url: Url,
 }
 
 impl VkApi {
-    pub fn new(service_token: String) -> Self {
+    pub fn new(service_token: String, base_url: Url) -> Self {
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .enable_http2()
+            .build();
+
         Self {
-            inner: VkApiBuilder::new(service_token).into(),
+            client: Client::builder().build(https),
+            service_token,
+            base_url,
         }
     }
 
@@ -19,8 +39,7 @@ impl VkApi {
         &self,
         task_id: Uuid,
     ) -> Result<CheckProcessingStatusResponse, VkApiError> {
-        self.inner
-            .send_request_with_wrapper(CheckProcessingStatusRequest { task_id })
+        self.send_request_with_wrapper(CheckProcessingStatusRequest { task_id })
             .await
     }
 
@@ -29,18 +48,178 @@ impl VkApi {
         audio: String,
         model: SpeechModel,
     ) -> Result<ProcessAudioResponse, VkApiError> {
-        self.inner
-            .send_request_with_wrapper(ProcessAudioRequest { audio, model })
+        self.send_request_with_wrapper(ProcessAudioRequest { audio, model })
             .await
     }
 
     pub async fn get_upload_url(&self) -> Result<UploadUrlResponse, VkApiError> {
-        self.inner
-            .send_request_with_wrapper(UploadUrlRequest {})
+        self.send_request_with_wrapper(UploadUrlRequest {}).await
+    }
+
+    /// Uploads form to url returned by `get_upload_url`.
+    /// Returns raw uploader response, which must be passed to `process_speech`.
+    pub async fn upload(&self, url: Url, form: Form<'static>) -> Result<String, VkApiError> {
+        let request = form
+            .set_body_convert::<Body, MultipartBody>(
+                Request::post(url.as_str()).header(ACCEPT, "application/json"),
+            )
+            .map_err(VkApiError::Http)?;
+
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(VkApiError::Request)?;
+
+        if !response.status().is_success() {
+            return Err(VkApiError::Status(response.status()));
+        }
+
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(VkApiError::Request)?;
+
+        String::from_utf8(body.to_vec())
+            .map_err(|e| VkApiError::IO(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))
+    }
+
+    async fn send_request_with_wrapper<W>(&self, wrapper: W) -> Result<W::Response, VkApiError>
+    where
+        W: VkApiWrapper + Serialize,
+    {
+        let url = format!(
+            "{}/method/{}",
+            self.base_url.as_str().trim_end_matches('/'),
+            W::get_method_name()
+        );
+
+        let body = serde_urlencoded::to_string(VkApiBody {
+            v: W::get_version(),
+            body: wrapper,
+        })
+        .map_err(VkApiError::RequestSerialize)?;
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header(ACCEPT, "application/json")
+            .header(AUTHORIZATION, format!("Bearer {}", self.service_token))
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(body))
+            .map_err(VkApiError::Http)?;
+
+        let response = self
+            .client
+            .request(request)
             .await
+            .map_err(VkApiError::Request)?;
+
+        if !response.status().is_success() {
+            return Err(VkApiError::Status(response.status()));
+        }
+
+        let body = hyper::body::aggregate(response.into_body())
+            .await
+            .map_err(VkApiError::Request)?;
+
+        match serde_json::from_reader(body.reader()).map_err(VkApiError::ResponseDeserialize)? {
+            Response::Success { response } => Ok(response),
+            Response::Error { error } => Err(VkApiError::Vk(error)),
+        }
+    }
+}
+
+/// VK API errors.
+/// `VkApiError::Vk` is an error of business logic, like expired token or incorrect request params.
+/// Other errors are about things around the request, like a serialization or network errors.
+#[derive(Debug)]
+pub enum VkApiError {
+    Http(hyper::http::Error),
+    Request(hyper::Error),
+    Status(hyper::StatusCode),
+    RequestSerialize(serde_urlencoded::ser::Error),
+    ResponseDeserialize(serde_json::Error),
+    Vk(VkError),
+    IO(std::io::Error),
+    Timeout,
+}
+
+impl VkApiError {
+    /// Network failures, server errors and rate limits, which may pass on retry.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            VkApiError::Request(_) | VkApiError::Timeout => true,
+            VkApiError::Status(s) => {
+                s.is_server_error() || *s == hyper::StatusCode::TOO_MANY_REQUESTS
+            }
+            VkApiError::Vk(e) => matches!(
+                e.error_code,
+                VK_UNKNOWN_ERROR
+                    | VK_TOO_MANY_REQUESTS
+                    | VK_FLOOD_CONTROL
+                    | VK_INTERNAL_ERROR
+                    | VK_RATE_LIMIT
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl Display for VkApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VkApiError::Http(e) => Display::fmt(e, f),
+            VkApiError::Request(e) => Display::fmt(e, f),
+            VkApiError::Status(s) => write!(f, "vk api responded with status {}", s),
+            VkApiError::RequestSerialize(e) => Display::fmt(e, f),
+            VkApiError::ResponseDeserialize(e) => Display::fmt(e, f),
+            VkApiError::Vk(e) => Display::fmt(e, f),
+            VkApiError::IO(e) => Display::fmt(e, f),
+            VkApiError::Timeout => write!(f, "vk api request timed out"),
+        }
+    }
+}
+
+impl std::error::Error for VkApiError {}
+
+const VK_UNKNOWN_ERROR: i64 = 1;
+const VK_TOO_MANY_REQUESTS: i64 = 6;
+const VK_FLOOD_CONTROL: i64 = 9;
+const VK_INTERNAL_ERROR: i64 = 10;
+const VK_RATE_LIMIT: i64 = 29;
+
+/// VK backend business logic errors.
+/// [More info about codes](https://dev.vk.com/reference/errors).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VkError {
+    pub error_code: i64,
+    pub error_msg: String,
+}
+
+impl Display for VkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "vk api error occurred. Code: {}, message: {}",
+            self.error_code, self.error_msg
+        )
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Response<T> {
+    Success { response: T },
+    Error { error: VkError },
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct VkApiBody<T> {
+    v: Version,
+    #[serde(flatten)]
+    body: T,
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 struct CheckProcessingStatusRequest {
     task_id: Uuid,
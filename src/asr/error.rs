@@ -0,0 +1,123 @@
+use crate::asr::client::VkApiError;
+use actix_web::http::StatusCode;
+use serde::{Serialize, Serializer};
+use std::fmt::{Display, Formatter};
+
+pub type AsrResult = Result<String, AsrError>;
+
+/// Speech recognition failures.
+/// Serialized as `{"code": "...", "error": "..."}` so clients can react to each case.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum AsrError {
+    /// Audio file wasn't uploaded to the recognition service
+    Upload(String),
+    /// VK API responded with business logic error
+    VkApi { code: i64, message: String },
+    /// Audio recording can't be converted to the format of the recognition service
+    Transcoding,
+    /// Speech in the audio recording can't be recognized
+    Recognition,
+    /// Voice activity detection found only silence in the audio recording
+    NoSpeech,
+    /// Internal error of the recognition service or wacr itself
+    Internal(String),
+    /// Recognition didn't finish before the deadline
+    Timeout,
+    /// Recognition service is unreachable or degraded
+    Unavailable(String),
+}
+
+impl AsrError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AsrError::Upload(_) => "upload_error",
+            AsrError::VkApi { .. } => "vk_api_error",
+            AsrError::Transcoding => "transcoding_error",
+            AsrError::Recognition => "recognition_error",
+            AsrError::NoSpeech => "no_speech",
+            AsrError::Internal(_) => "internal_error",
+            AsrError::Timeout => "timeout",
+            AsrError::Unavailable(_) => "unavailable",
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            AsrError::Upload(_) | AsrError::VkApi { .. } => StatusCode::BAD_GATEWAY,
+            AsrError::Transcoding | AsrError::Recognition | AsrError::NoSpeech => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            AsrError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AsrError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            AsrError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl Display for AsrError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AsrError::Upload(e) => write!(f, "fail to upload audio: {}", e),
+            AsrError::VkApi { code, message } => {
+                write!(
+                    f,
+                    "vk api error occurred. Code: {}, message: {}",
+                    code, message
+                )
+            }
+            AsrError::Transcoding => {
+                write!(f, "error transcoding audio recording to internal format")
+            }
+            AsrError::Recognition => {
+                write!(f, "speech recognition error, difficulty in recognition")
+            }
+            AsrError::NoSpeech => write!(f, "there is no speech in the audio recording"),
+            AsrError::Internal(e) => write!(f, "internal error of speech recognition: {}", e),
+            AsrError::Timeout => write!(f, "speech recognition deadline exceeded"),
+            AsrError::Unavailable(e) => write!(f, "speech recognition is unavailable: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AsrError {}
+
+impl Serialize for AsrError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct AsrErrorBody {
+            code: &'static str,
+            error: String,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            vk_error_code: Option<i64>,
+        }
+
+        AsrErrorBody {
+            code: self.code(),
+            error: self.to_string(),
+            vk_error_code: match self {
+                AsrError::VkApi { code, .. } => Some(*code),
+                _ => None,
+            },
+        }
+        .serialize(serializer)
+    }
+}
+
+impl From<VkApiError> for AsrError {
+    fn from(e: VkApiError) -> Self {
+        match e {
+            VkApiError::Vk(e) => AsrError::VkApi {
+                code: e.error_code,
+                message: e.error_msg,
+            },
+            e if e.is_transient() => AsrError::Unavailable(e.to_string()),
+            e => AsrError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for AsrError {
+    fn from(e: std::io::Error) -> Self {
+        AsrError::Internal(e.to_string())
+    }
+}
@@ -0,0 +1,88 @@
+use crate::asr::client::SpeechModel;
+use crate::asr::processor::{AsrJob, AsrProcessor};
+use crate::asr::recognizer::SpeechRecognizer;
+use crate::asr::AsrConfig;
+use crate::events::broker::EventBroker;
+use crate::garbage::collector::GarbageCollector;
+use crate::webhook::dispatcher::WebhookDispatcher;
+use crate::webrtc::find_audio_path;
+use crate::{UserAsrProcessorStorage, UserId};
+use actix::Addr;
+use dashmap::mapref::entry::Entry;
+use std::path::PathBuf;
+use std::sync::Arc;
+use url::Url;
+use uuid::Uuid;
+
+/// Starts recognitions of the recorded tracks, it's shared by the api and the session control channel.
+#[derive(Clone)]
+pub struct AsrLauncher {
+    pub storage: Arc<UserAsrProcessorStorage>,
+    pub recognizer: Arc<dyn SpeechRecognizer>,
+    pub config: AsrConfig,
+    /// Directory of the recordings
+    pub dir: PathBuf,
+    /// Result is posted to this url when the request has no own callback
+    pub callback: Option<Url>,
+    pub garbage_collector: Arc<Addr<GarbageCollector>>,
+    pub webhook_dispatcher: Arc<Addr<WebhookDispatcher>>,
+    pub events: Arc<Addr<EventBroker>>,
+}
+
+/// Recognition of the track requested by the user.
+pub struct AsrRequest {
+    pub user_id: UserId,
+    pub session_id: Uuid,
+    pub track: usize,
+    pub speech_model: SpeechModel,
+    pub callback: Option<Url>,
+}
+
+impl AsrLauncher {
+    pub fn is_started(&self, user_id: UserId, session_id: Uuid, track: usize) -> bool {
+        self.storage
+            .get(&user_id)
+            .is_some_and(|s| s.contains_key(&(session_id, track)))
+    }
+
+    /// Returns the processor of the track, it's started once.
+    /// `None` if the track wasn't recorded.
+    pub fn launch(&self, request: AsrRequest) -> Option<Addr<AsrProcessor>> {
+        let AsrRequest {
+            user_id,
+            session_id,
+            track,
+            speech_model,
+            callback,
+        } = request;
+
+        let storage = self.storage.entry(user_id).or_default().clone();
+
+        let processor = match storage.entry((session_id, track)) {
+            Entry::Occupied(e) => e.get().clone(),
+            Entry::Vacant(e) => {
+                let (audio_path, _) = find_audio_path(session_id, track, self.dir.clone())?;
+
+                e.insert(AsrProcessor::new(
+                    AsrJob {
+                        id: session_id,
+                        track,
+                        user_id,
+                        audio_path,
+                        speech_model,
+                        deadline: self.config.deadline,
+                        callback: callback.or_else(|| self.callback.clone()),
+                        trimmer: self.config.trimmer.clone(),
+                    },
+                    self.recognizer.clone(),
+                    self.garbage_collector.clone(),
+                    self.webhook_dispatcher.clone(),
+                    self.events.clone(),
+                ))
+                .clone()
+            }
+        };
+
+        Some(processor)
+    }
+}
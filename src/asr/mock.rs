@@ -0,0 +1,154 @@
+//! Mock of VK ASR API for tests without network.
+//! Implements `asr.getUploadUrl`, the upload endpoint, `asr.process` and `asr.checkStatus`
+//! with outcomes scripted by `MockScript`.
+use crate::asr::client::VkError;
+use actix_web::dev::ServerHandle;
+use actix_web::{web, App, HttpResponse, HttpServer};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use url::Url;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Default)]
+pub struct MockScript {
+    /// Error returned by `asr.getUploadUrl`
+    pub upload_url_error: Option<VkError>,
+    /// Http statuses returned by the first calls of the upload endpoint before success
+    pub upload_statuses: Vec<u16>,
+    /// Error returned by `asr.process`
+    pub process_error: Option<VkError>,
+    /// Sequence of `asr.checkStatus` responses, the last one is repeated
+    pub statuses: Vec<MockStatus>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "status")]
+pub enum MockStatus {
+    Processing,
+    Finished { text: String },
+    InternalError,
+    TranscodingError,
+    RecognitionError,
+}
+
+#[derive(Default)]
+pub struct MockCalls {
+    pub get_upload_url: AtomicUsize,
+    pub upload: AtomicUsize,
+    pub process: AtomicUsize,
+    pub check_status: AtomicUsize,
+}
+
+pub struct MockVkAsr {
+    pub url: Url,
+    pub calls: Arc<MockCalls>,
+    handle: ServerHandle,
+}
+
+struct MockState {
+    url: Url,
+    script: MockScript,
+    statuses: Mutex<VecDeque<MockStatus>>,
+    upload_statuses: Mutex<VecDeque<u16>>,
+    calls: Arc<MockCalls>,
+}
+
+impl MockVkAsr {
+    pub async fn start(script: MockScript) -> std::io::Result<Self> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let url = Url::parse(&format!("http://{}", listener.local_addr()?))
+            .map_err(std::io::Error::other)?;
+        let calls = Arc::new(MockCalls::default());
+
+        let state = web::Data::new(MockState {
+            url: url.clone(),
+            statuses: Mutex::new(script.statuses.iter().cloned().collect()),
+            upload_statuses: Mutex::new(script.upload_statuses.iter().cloned().collect()),
+            script,
+            calls: calls.clone(),
+        });
+
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(state.clone())
+                .route("/method/asr.getUploadUrl", web::post().to(get_upload_url))
+                .route("/upload", web::post().to(upload))
+                .route("/method/asr.process", web::post().to(process))
+                .route("/method/asr.checkStatus", web::post().to(check_status))
+        })
+        .workers(1)
+        .listen(listener)?
+        .run();
+
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        Ok(Self { url, calls, handle })
+    }
+
+    pub async fn stop(self) {
+        self.handle.stop(false).await
+    }
+}
+
+async fn get_upload_url(state: web::Data<MockState>) -> HttpResponse {
+    state.calls.get_upload_url.fetch_add(1, Ordering::SeqCst);
+
+    match &state.script.upload_url_error {
+        Some(error) => vk_error(error),
+        None => vk_response(json!({ "upload_url": state.url.join("upload").unwrap() })),
+    }
+}
+
+async fn upload(state: web::Data<MockState>, _body: web::Bytes) -> HttpResponse {
+    state.calls.upload.fetch_add(1, Ordering::SeqCst);
+
+    let status = state.upload_statuses.lock().unwrap().pop_front();
+    match status {
+        Some(status) => HttpResponse::build(
+            actix_web::http::StatusCode::from_u16(status).expect("invalid mock status"),
+        )
+        .finish(),
+        None => HttpResponse::Ok().json(json!({ "audio_id": "mock" })),
+    }
+}
+
+async fn process(state: web::Data<MockState>) -> HttpResponse {
+    state.calls.process.fetch_add(1, Ordering::SeqCst);
+
+    match &state.script.process_error {
+        Some(error) => vk_error(error),
+        None => vk_response(json!({ "task_id": Uuid::new_v4() })),
+    }
+}
+
+async fn check_status(state: web::Data<MockState>) -> HttpResponse {
+    state.calls.check_status.fetch_add(1, Ordering::SeqCst);
+
+    let status = {
+        let mut statuses = state.statuses.lock().unwrap();
+        if statuses.len() > 1 {
+            statuses.pop_front()
+        } else {
+            statuses.front().cloned()
+        }
+    }
+    .unwrap_or(MockStatus::Processing);
+
+    let mut response = serde_json::to_value(status).unwrap();
+    response["id"] = json!(Uuid::new_v4());
+
+    vk_response(response)
+}
+
+fn vk_response(response: serde_json::Value) -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "response": response }))
+}
+
+fn vk_error(error: &VkError) -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "error": error }))
+}
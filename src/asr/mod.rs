@@ -1,9 +1,49 @@
+use crate::audio::vad::VoiceTrimmer;
 use crate::AsrProcessor;
 use actix::Addr;
 use dashmap::DashMap;
+use std::str::FromStr;
+use std::time::Duration;
 use uuid::Uuid;
 
+pub mod backoff;
 pub mod client;
+pub mod error;
+pub mod launcher;
+#[cfg(test)]
+pub mod mock;
 pub mod processor;
+pub mod recognizer;
+pub mod retry;
+pub mod vk;
+pub mod whisper;
 
-pub type AsrProcessorStorage = DashMap<Uuid, Addr<AsrProcessor>>;
+/// Recognitions of the session tracks, keyed by session id and track number.
+pub type AsrProcessorStorage = DashMap<(Uuid, usize), Addr<AsrProcessor>>;
+
+/// Speech recognition engine used by `AsrProcessor`, selected at startup.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AsrBackend {
+    Vk,
+    Whisper,
+}
+
+impl FromStr for AsrBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vk" => Ok(AsrBackend::Vk),
+            "whisper" => Ok(AsrBackend::Whisper),
+            b => Err(format!("unknown asr backend: {}", b)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AsrConfig {
+    /// Max time of the whole recognition, from upload to the final result
+    pub deadline: Duration,
+    /// Silence is trimmed before recognition when it's set
+    pub trimmer: Option<VoiceTrimmer>,
+}
@@ -1,107 +1,96 @@
-use crate::asr::client::{CheckProcessingStatusResponse, SpeechModel};
+use crate::asr::client::SpeechModel;
+use crate::asr::error::{AsrError, AsrResult};
+use crate::asr::recognizer::{AsrProgress, SpeechRecognizer};
+use crate::audio::vad::VoiceTrimmer;
+use crate::events::broker::{EventBroker, Publish};
+use crate::events::SessionEvent;
 use crate::garbage::collector::{ClearAsr, GarbageCollector};
-use crate::webrtc::get_audio_path;
-use crate::{UserId, VkApi};
+use crate::webhook::dispatcher::{SendWebhook, WebhookDispatcher};
+use crate::UserId;
 use actix::prelude::*;
-use log::error;
+use log::{error, warn};
+use serde::Serialize;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
+use url::Url;
 use uuid::Uuid;
-use vkclient::upload::{Form, VkUploader};
 
 pub struct AsrProcessor {
     id: Uuid,
+    track: usize,
     user_id: UserId,
-    result: Option<Arc<std::io::Result<String>>>,
-    senders: Vec<futures::channel::oneshot::Sender<Arc<std::io::Result<String>>>>,
+    result: Option<Arc<AsrResult>>,
+    started: bool,
+    senders: Vec<futures::channel::oneshot::Sender<Arc<AsrResult>>>,
     garbage_collector: Arc<Addr<GarbageCollector>>,
+    callback: Option<Url>,
+    webhook_dispatcher: Arc<Addr<WebhookDispatcher>>,
+    events: Arc<Addr<EventBroker>>,
+}
+
+/// Recognition requested for the recorded session.
+pub struct AsrJob {
+    pub id: Uuid,
+    /// Number of the recorded session track
+    pub track: usize,
+    pub user_id: UserId,
+    pub audio_path: PathBuf,
+    pub speech_model: SpeechModel,
+    pub deadline: Duration,
+    /// Result is posted to this url when recognition is done
+    pub callback: Option<Url>,
+    pub trimmer: Option<VoiceTrimmer>,
 }
 
 impl AsrProcessor {
     pub fn new(
-        id: Uuid,
-        user_id: UserId,
-        client: Arc<VkApi>,
-        uploader: Arc<VkUploader>,
-        dir: PathBuf,
+        job: AsrJob,
+        recognizer: Arc<dyn SpeechRecognizer>,
         garbage_collector: Arc<Addr<GarbageCollector>>,
-        speech_model: SpeechModel,
+        webhook_dispatcher: Arc<Addr<WebhookDispatcher>>,
+        events: Arc<Addr<EventBroker>>,
     ) -> Addr<Self> {
+        let AsrJob {
+            id,
+            track,
+            user_id,
+            audio_path,
+            speech_model,
+            deadline,
+            callback,
+            trimmer,
+        } = job;
+
         Self::create(|ctx| {
             let processor = Self {
                 id,
+                track,
                 user_id,
                 result: None,
+                started: false,
                 senders: vec![],
                 garbage_collector,
+                callback,
+                webhook_dispatcher,
+                events,
             };
             let addr = ctx.address();
 
             ctx.spawn(
                 async move {
-                    let audio_path = get_audio_path(id, dir);
-                    let response: std::io::Result<String> = async {
-                        let upload_url = client
-                            .get_upload_url()
-                            .await
-                            .map_err(|e| {
-                                std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
-                            })?
-                            .upload_url;
-
-                        let mut form = Form::default();
-                        form.add_file("file", audio_path)?;
-
-                        let uploader_info =
-                            uploader.upload(upload_url, form).await.map_err(|e| {
-                                std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
-                            })?;
-
-                        let process_response = client
-                            .process_speech(uploader_info, speech_model)
-                            .await
-                            .map_err(|e| {
-                                std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
-                            })?;
-
-                        loop {
-                            let status = client
-                                .check_status(process_response.task_id)
-                                .await
-                                .map_err(|e| {
-                                    std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
-                                })?;
-
-                            match status {
-                                CheckProcessingStatusResponse::Processing { .. } => {
-                                    actix_web::rt::time::sleep(Duration::from_secs(1)).await
-                                }
-                                CheckProcessingStatusResponse::Finished { text, .. } => {
-                                    return Ok(text)
-                                }
-                                CheckProcessingStatusResponse::InternalError { .. } => {
-                                    return Err(std::io::Error::new(
-                                        std::io::ErrorKind::Other,
-                                        "internal error of the VK speech recognition service",
-                                    ))
-                                }
-                                CheckProcessingStatusResponse::TranscodingError { .. } => {
-                                    return Err(std::io::Error::new(
-                                        std::io::ErrorKind::Other,
-                                        "error transcoding audio recording to internal format",
-                                    ))
-                                }
-                                CheckProcessingStatusResponse::RecognitionError { .. } => {
-                                    return Err(std::io::Error::new(
-                                        std::io::ErrorKind::Other,
-                                        "speech recognition error, difficulty in recognition",
-                                    ))
-                                }
-                            }
-                        }
-                    }
-                    .await;
+                    let response = actix_web::rt::time::timeout(
+                        deadline,
+                        recognize(
+                            recognizer,
+                            trimmer,
+                            audio_path,
+                            speech_model,
+                            addr.clone().recipient(),
+                        ),
+                    )
+                    .await
+                    .unwrap_or(Err(AsrError::Timeout));
 
                     if let Err(e) = addr.send(AcceptResult(response)).await {
                         error!("fail to accept result after processing {}", e)
@@ -115,6 +104,55 @@ impl AsrProcessor {
     }
 }
 
+/// Recognizes the recording, its silence is trimmed first if `trimmer` is set.
+async fn recognize(
+    recognizer: Arc<dyn SpeechRecognizer>,
+    trimmer: Option<VoiceTrimmer>,
+    audio_path: PathBuf,
+    speech_model: SpeechModel,
+    progress: Recipient<AsrProgress>,
+) -> AsrResult {
+    let trimmed = match trimmer {
+        None => None,
+        Some(t) => match t.trim(audio_path.clone()).await {
+            Ok(None) => return Err(AsrError::NoSpeech),
+            Ok(trimmed) => trimmed,
+            Err(e) => {
+                warn!(
+                    "fail to trim silence, the whole recording is recognized: {}",
+                    e
+                );
+                None
+            }
+        },
+    };
+
+    let result = recognizer
+        .recognize(
+            trimmed.clone().unwrap_or(audio_path),
+            speech_model,
+            progress,
+        )
+        .await;
+
+    if let Some(Err(e)) = trimmed.map(std::fs::remove_file) {
+        warn!("fail to remove trimmed recording: {}", e);
+    }
+
+    result
+}
+
+impl AsrProcessor {
+    fn state(&self) -> AsrState {
+        match self.result.as_deref() {
+            Some(Ok(text)) => AsrState::Finished { text: text.clone() },
+            Some(Err(e)) => AsrState::Failed(e.clone()),
+            None if self.started => AsrState::Processing,
+            None => AsrState::Pending,
+        }
+    }
+}
+
 impl Actor for AsrProcessor {
     type Context = Context<Self>;
 }
@@ -135,6 +173,32 @@ impl Handler<WaitForResponse> for AsrProcessor {
     }
 }
 
+impl Handler<GetState> for AsrProcessor {
+    type Result = MessageResult<GetState>;
+
+    fn handle(&mut self, _: GetState, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.state())
+    }
+}
+
+impl Handler<AsrProgress> for AsrProcessor {
+    type Result = ();
+
+    fn handle(&mut self, progress: AsrProgress, _ctx: &mut Self::Context) -> Self::Result {
+        self.started = true;
+
+        let event = match progress {
+            AsrProgress::UploadStarted => SessionEvent::AsrUploadStarted { track: self.track },
+            AsrProgress::TaskAssigned(task_id) => SessionEvent::AsrTaskAssigned {
+                track: self.track,
+                task_id,
+            },
+            AsrProgress::Processing => SessionEvent::AsrProcessing { track: self.track },
+        };
+        self.events.do_send(Publish(self.id, event));
+    }
+}
+
 impl Handler<AcceptResult> for AsrProcessor {
     type Result = ();
 
@@ -151,8 +215,32 @@ impl Handler<AcceptResult> for AsrProcessor {
             let _ = sender.send(r.clone());
         }
 
+        let event = match r.as_ref() {
+            Ok(text) => SessionEvent::AsrFinished {
+                track: self.track,
+                text: text.clone(),
+            },
+            Err(e) => SessionEvent::AsrFailed {
+                track: self.track,
+                error: e.clone(),
+            },
+        };
+        self.events.do_send(Publish(self.id, event));
+
+        if let Some(url) = self.callback.clone() {
+            match serde_json::to_value(WebhookPayload {
+                session_id: self.id,
+                track: self.track,
+                user_id: self.user_id.0,
+                state: self.state(),
+            }) {
+                Ok(payload) => self.webhook_dispatcher.do_send(SendWebhook(url, payload)),
+                Err(e) => error!("fail to serialize webhook payload {}", e),
+            }
+        }
+
         self.garbage_collector
-            .do_send(ClearAsr(self.user_id, self.id))
+            .do_send(ClearAsr(self.user_id, self.id, self.track))
     }
 }
 
@@ -160,8 +248,31 @@ impl Handler<AcceptResult> for AsrProcessor {
 #[rtype(result = "ProcessResponse")]
 pub struct WaitForResponse;
 
-pub struct ProcessResponse(pub futures::channel::oneshot::Receiver<Arc<std::io::Result<String>>>);
+pub struct ProcessResponse(pub futures::channel::oneshot::Receiver<Arc<AsrResult>>);
 
 #[derive(Message)]
 #[rtype(result = "()")]
-struct AcceptResult(std::io::Result<String>);
+struct AcceptResult(AsrResult);
+
+#[derive(Message)]
+#[rtype(result = "AsrState")]
+pub struct GetState;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "state")]
+pub enum AsrState {
+    Pending,
+    Processing,
+    Finished { text: String },
+    Failed(AsrError),
+}
+
+#[derive(Serialize)]
+struct WebhookPayload {
+    session_id: Uuid,
+    track: usize,
+    user_id: i64,
+    #[serde(flatten)]
+    state: AsrState,
+}
@@ -0,0 +1,28 @@
+use crate::asr::client::SpeechModel;
+use crate::asr::error::AsrResult;
+use actix::{Message, Recipient};
+use futures::future::BoxFuture;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Speech recognition engine driven by `AsrProcessor`.
+/// Takes the audio file recorded by the webrtc session and returns recognized text.
+/// Intermediate steps are reported to `progress`.
+pub trait SpeechRecognizer: Send + Sync {
+    fn recognize(
+        &self,
+        audio_path: PathBuf,
+        speech_model: SpeechModel,
+        progress: Recipient<AsrProgress>,
+    ) -> BoxFuture<'_, AsrResult>;
+}
+
+/// Steps of recognition before the result is known.
+/// Recognizers without upload or remote tasks report only `Processing`.
+#[derive(Message, Debug, Clone, Copy)]
+#[rtype(result = "()")]
+pub enum AsrProgress {
+    UploadStarted,
+    TaskAssigned(Uuid),
+    Processing,
+}
@@ -0,0 +1,227 @@
+use crate::asr::backoff::BackoffConfig;
+use log::warn;
+use rand::Rng;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Max number of attempts including the first one
+    pub attempts: usize,
+    /// Delays between attempts, randomized by jitter
+    pub backoff: BackoffConfig,
+    /// Max time of a single attempt
+    pub timeout: Duration,
+}
+
+impl RetryPolicy {
+    /// Runs `f` until it succeeds, returns not transient error or attempts are over.
+    /// Timed out attempts are converted by `on_timeout` and treated as transient.
+    pub async fn run<T, E, F, Fut>(
+        &self,
+        name: &str,
+        mut f: F,
+        is_transient: impl Fn(&E) -> bool,
+        on_timeout: impl Fn() -> E,
+    ) -> Result<T, E>
+    where
+        E: std::fmt::Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut delays = self.backoff.delays();
+        let mut attempt = 1;
+        loop {
+            let result = actix_web::rt::time::timeout(self.timeout, f())
+                .await
+                .unwrap_or_else(|_| Err(on_timeout()));
+
+            match result {
+                Err(e) if attempt < self.attempts && is_transient(&e) => {
+                    let delay = delays.next().map(with_jitter).unwrap_or_default();
+                    warn!(
+                        target: "retry",
+                        "{} attempt {} failed, retry in {}ms: {}",
+                        name,
+                        attempt,
+                        delay.as_millis(),
+                        e
+                    );
+                    actix_web::rt::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Randomizes delay in range [delay / 2, delay], so retries of parallel jobs don't come at once.
+fn with_jitter(delay: Duration) -> Duration {
+    let half = delay / 2;
+    half + half.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+}
+
+/// Stops calls to degraded service after `failure_threshold` failures in a row.
+/// After `cooldown` a single probe call is allowed, it closes the breaker on success.
+pub struct CircuitBreaker {
+    failure_threshold: usize,
+    cooldown: Duration,
+    state: Mutex<CircuitState>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum CircuitState {
+    Closed { failures: usize },
+    Open { since: Instant },
+    HalfOpen { since: Instant },
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: usize, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            state: Mutex::new(CircuitState::Closed { failures: 0 }),
+        }
+    }
+
+    /// Returns false if the call must fail fast.
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            CircuitState::Closed { .. } => true,
+            CircuitState::Open { since } | CircuitState::HalfOpen { since }
+                if since.elapsed() >= self.cooldown =>
+            {
+                *state = CircuitState::HalfOpen {
+                    since: Instant::now(),
+                };
+                true
+            }
+            CircuitState::Open { .. } | CircuitState::HalfOpen { .. } => false,
+        }
+    }
+
+    pub fn record_success(&self) {
+        *self.state.lock().unwrap() = CircuitState::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        *state = match *state {
+            CircuitState::Closed { failures } if failures + 1 < self.failure_threshold => {
+                CircuitState::Closed {
+                    failures: failures + 1,
+                }
+            }
+            _ => {
+                warn!(target: "retry", "circuit breaker is open");
+                CircuitState::Open {
+                    since: Instant::now(),
+                }
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asr::backoff::BackoffConfig;
+    use crate::asr::retry::{CircuitBreaker, RetryPolicy};
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    fn policy(attempts: usize) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            backoff: BackoffConfig {
+                initial: Duration::from_millis(1),
+                max: Duration::from_millis(1),
+                multiplier: 1.0,
+            },
+            timeout: Duration::from_millis(100),
+        }
+    }
+
+    #[actix_web::test]
+    async fn retries_transient_errors() {
+        let calls = Cell::new(0);
+        let result: Result<(), &str> = policy(3)
+            .run(
+                "test",
+                || async {
+                    calls.set(calls.get() + 1);
+                    Err("transient")
+                },
+                |_| true,
+                || "timeout",
+            )
+            .await;
+
+        assert_eq!(result, Err("transient"));
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[actix_web::test]
+    async fn stops_on_permanent_error() {
+        let calls = Cell::new(0);
+        let result: Result<(), &str> = policy(3)
+            .run(
+                "test",
+                || async {
+                    calls.set(calls.get() + 1);
+                    if calls.get() == 1 {
+                        Err("transient")
+                    } else {
+                        Err("permanent")
+                    }
+                },
+                |e| *e == "transient",
+                || "timeout",
+            )
+            .await;
+
+        assert_eq!(result, Err("permanent"));
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[actix_web::test]
+    async fn timed_out_attempt_is_retried() {
+        let calls = Cell::new(0);
+        let result: Result<usize, &str> = policy(2)
+            .run(
+                "test",
+                || async {
+                    calls.set(calls.get() + 1);
+                    if calls.get() == 1 {
+                        actix_web::rt::time::sleep(Duration::from_secs(1)).await;
+                    }
+                    Ok(calls.get())
+                },
+                |e| *e == "timeout",
+                || "timeout",
+            )
+            .await;
+
+        assert_eq!(result, Ok(2));
+    }
+
+    #[test]
+    fn circuit_breaker_opens_and_probes() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(20));
+
+        breaker.record_failure();
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert!(!breaker.allow());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.allow());
+        assert!(!breaker.allow(), "only one probe is allowed");
+
+        breaker.record_success();
+        assert!(breaker.allow());
+    }
+}
@@ -0,0 +1,140 @@
+use crate::asr::backoff::BackoffConfig;
+use crate::asr::client::{CheckProcessingStatusResponse, SpeechModel, VkApi, VkApiError};
+use crate::asr::error::{AsrError, AsrResult};
+use crate::asr::recognizer::{AsrProgress, SpeechRecognizer};
+use crate::asr::retry::{CircuitBreaker, RetryPolicy};
+use actix::Recipient;
+use futures::future::BoxFuture;
+use hyper_multipart_rfc7578::client::multipart::Form;
+use std::future::Future;
+use std::path::PathBuf;
+
+/// Recognizer backed by [VK ASR API](https://dev.vk.com/api/voice-tech).
+pub struct VkRecognizer {
+    client: VkApi,
+    polling: BackoffConfig,
+    retry: RetryPolicy,
+    breaker: CircuitBreaker,
+}
+
+impl VkRecognizer {
+    pub fn new(
+        client: VkApi,
+        polling: BackoffConfig,
+        retry: RetryPolicy,
+        breaker: CircuitBreaker,
+    ) -> Self {
+        Self {
+            client,
+            polling,
+            retry,
+            breaker,
+        }
+    }
+
+    /// Calls VK API with retries of transient failures.
+    /// Fails fast while the circuit breaker is open.
+    async fn call<T, F, Fut>(&self, name: &str, f: F) -> Result<T, AsrError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, VkApiError>>,
+    {
+        if !self.breaker.allow() {
+            return Err(AsrError::Unavailable(
+                "VK speech recognition service is degraded".to_string(),
+            ));
+        }
+
+        match self
+            .retry
+            .run(name, f, VkApiError::is_transient, || VkApiError::Timeout)
+            .await
+        {
+            Ok(r) => {
+                self.breaker.record_success();
+                Ok(r)
+            }
+            Err(e) => {
+                if e.is_transient() {
+                    self.breaker.record_failure();
+                }
+                Err(e.into())
+            }
+        }
+    }
+}
+
+impl SpeechRecognizer for VkRecognizer {
+    fn recognize(
+        &self,
+        audio_path: PathBuf,
+        speech_model: SpeechModel,
+        progress: Recipient<AsrProgress>,
+    ) -> BoxFuture<'_, AsrResult> {
+        Box::pin(async move {
+            let upload_url = self
+                .call("asr.getUploadUrl", || self.client.get_upload_url())
+                .await?
+                .upload_url;
+
+            progress.do_send(AsrProgress::UploadStarted);
+            let uploader_info = self
+                .call("upload", || {
+                    let upload_url = upload_url.clone();
+                    let audio_path = audio_path.clone();
+                    async move {
+                        let mut form = Form::default();
+                        form.add_file("file", audio_path).map_err(VkApiError::IO)?;
+                        self.client.upload(upload_url, form).await
+                    }
+                })
+                .await
+                .map_err(|e| match e {
+                    AsrError::Unavailable(e) | AsrError::Internal(e) => AsrError::Upload(e),
+                    e => e,
+                })?;
+
+            let process_response = self
+                .call("asr.process", || {
+                    self.client
+                        .process_speech(uploader_info.clone(), speech_model)
+                })
+                .await?;
+            progress.do_send(AsrProgress::TaskAssigned(process_response.task_id));
+
+            let mut polling_delays = self.polling.delays();
+            let mut processing = false;
+            loop {
+                let status = self
+                    .call("asr.checkStatus", || {
+                        self.client.check_status(process_response.task_id)
+                    })
+                    .await?;
+
+                match status {
+                    CheckProcessingStatusResponse::Processing { .. } => {
+                        if !processing {
+                            processing = true;
+                            progress.do_send(AsrProgress::Processing);
+                        }
+                        if let Some(delay) = polling_delays.next() {
+                            actix_web::rt::time::sleep(delay).await
+                        }
+                    }
+                    CheckProcessingStatusResponse::Finished { text, .. } => return Ok(text),
+                    CheckProcessingStatusResponse::InternalError { .. } => {
+                        return Err(AsrError::Internal(
+                            "internal error of the VK speech recognition service".to_string(),
+                        ))
+                    }
+                    CheckProcessingStatusResponse::TranscodingError { .. } => {
+                        return Err(AsrError::Transcoding)
+                    }
+                    CheckProcessingStatusResponse::RecognitionError { .. } => {
+                        return Err(AsrError::Recognition)
+                    }
+                }
+            }
+        })
+    }
+}
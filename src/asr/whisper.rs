@@ -0,0 +1,103 @@
+use crate::asr::client::SpeechModel;
+use crate::asr::error::{AsrError, AsrResult};
+use crate::asr::recognizer::{AsrProgress, SpeechRecognizer};
+use crate::audio::transcode::Transcoder;
+use actix::Recipient;
+use futures::future::BoxFuture;
+use log::{debug, error, warn};
+use std::path::PathBuf;
+use tokio::process::Command;
+
+/// whisper.cpp accepts only 16kHz mono PCM input.
+const WHISPER_SAMPLE_RATE: u32 = 16000;
+
+/// Offline recognizer running [whisper.cpp](https://github.com/ggerganov/whisper.cpp) on the CPU.
+/// Recorded audio is decoded to WAV with ffmpeg and passed to the whisper.cpp command line binary.
+pub struct WhisperRecognizer {
+    config: WhisperConfig,
+    transcoder: Transcoder,
+}
+
+#[derive(Debug, Clone)]
+pub struct WhisperConfig {
+    pub bin: PathBuf,
+    pub model: PathBuf,
+    pub language: String,
+    pub threads: usize,
+}
+
+impl WhisperRecognizer {
+    pub fn new(config: WhisperConfig, transcoder: Transcoder) -> Self {
+        Self { config, transcoder }
+    }
+}
+
+impl SpeechRecognizer for WhisperRecognizer {
+    fn recognize(
+        &self,
+        audio_path: PathBuf,
+        speech_model: SpeechModel,
+        progress: Recipient<AsrProgress>,
+    ) -> BoxFuture<'_, AsrResult> {
+        Box::pin(async move {
+            debug!(target: "whisper", "speech model {:?} is ignored by whisper", speech_model);
+            progress.do_send(AsrProgress::Processing);
+
+            // decoded audio is removed also when the recognition is dropped by the deadline
+            let wav = DecodedAudio(audio_path.with_extension("whisper.wav"));
+            self.transcoder
+                .to_pcm_wav(audio_path, wav.0.clone(), WHISPER_SAMPLE_RATE, 1)
+                .await
+                .map_err(|e| {
+                    error!(target: "whisper", "fail to decode audio: {}", e);
+                    AsrError::Transcoding
+                })?;
+
+            // whisper is killed when the recognition is dropped
+            let output = Command::new(&self.config.bin)
+                .arg("--model")
+                .arg(&self.config.model)
+                .arg("--language")
+                .arg(&self.config.language)
+                .arg("--threads")
+                .arg(self.config.threads.to_string())
+                .arg("--no-timestamps")
+                .arg("--no-prints")
+                .arg("--file")
+                .arg(&wav.0)
+                .kill_on_drop(true)
+                .output()
+                .await;
+            drop(wav);
+
+            let output = output?;
+            if !output.status.success() {
+                error!(
+                    target: "whisper",
+                    "whisper exited with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+                return Err(AsrError::Recognition);
+            }
+
+            Ok(String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty())
+                .collect::<Vec<_>>()
+                .join(" "))
+        })
+    }
+}
+
+/// Decoded audio file removed when it's dropped.
+struct DecodedAudio(PathBuf);
+
+impl Drop for DecodedAudio {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            warn!(target: "whisper", "fail to remove decoded audio {:?}: {}", self.0, e);
+        }
+    }
+}
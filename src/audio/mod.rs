@@ -0,0 +1,27 @@
+use serde::{Deserialize, Serialize};
+
+pub mod transcode;
+pub mod vad;
+
+/// Container of the stored recording.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioFormat {
+    /// Opus in Ogg
+    Ogg,
+    /// Signed 16 bit PCM in WAV
+    Wav,
+    Flac,
+}
+
+impl AudioFormat {
+    pub const ALL: [AudioFormat; 3] = [AudioFormat::Ogg, AudioFormat::Wav, AudioFormat::Flac];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Ogg => "ogg",
+            AudioFormat::Wav => "wav",
+            AudioFormat::Flac => "flac",
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use crate::audio::AudioFormat;
+use log::debug;
+use std::path::PathBuf;
+use tokio::process::Command;
+
+/// Converts audio files between formats with external [ffmpeg](https://ffmpeg.org) binary.
+#[derive(Debug, Clone)]
+pub struct Transcoder {
+    ffmpeg: PathBuf,
+}
+
+impl Transcoder {
+    pub fn new(ffmpeg: PathBuf) -> Self {
+        Self { ffmpeg }
+    }
+
+    /// Decodes any input supported by ffmpeg to signed 16 bit PCM WAV file.
+    pub async fn to_pcm_wav(
+        &self,
+        input: PathBuf,
+        output: PathBuf,
+        sample_rate: u32,
+        channels: u16,
+    ) -> std::io::Result<()> {
+        self.run(vec![
+            "-i".into(),
+            input.into_os_string().into_string().map_err(invalid_path)?,
+            "-ar".into(),
+            sample_rate.to_string(),
+            "-ac".into(),
+            channels.to_string(),
+            "-c:a".into(),
+            "pcm_s16le".into(),
+            output
+                .into_os_string()
+                .into_string()
+                .map_err(invalid_path)?,
+        ])
+        .await
+    }
+
+    /// Converts the recording to another container, the channels are kept if `channels` isn't set.
+    /// `input_format` is required for raw streams without a container, like `g722`.
+    pub async fn convert(
+        &self,
+        input: PathBuf,
+        input_format: Option<&str>,
+        output: PathBuf,
+        format: AudioFormat,
+        channels: Option<u16>,
+    ) -> std::io::Result<()> {
+        let mut args = vec![];
+        if let Some(f) = input_format {
+            args.extend(["-f".to_string(), f.to_string()]);
+        }
+        args.extend([
+            "-i".into(),
+            input.into_os_string().into_string().map_err(invalid_path)?,
+        ]);
+        if let Some(c) = channels {
+            args.extend(["-ac".to_string(), c.to_string()]);
+        }
+        args.extend([
+            "-c:a".into(),
+            match format {
+                AudioFormat::Ogg => "libopus",
+                AudioFormat::Wav => "pcm_s16le",
+                AudioFormat::Flac => "flac",
+            }
+            .into(),
+            output
+                .into_os_string()
+                .into_string()
+                .map_err(invalid_path)?,
+        ]);
+
+        self.run(args).await
+    }
+
+    async fn run(&self, args: Vec<String>) -> std::io::Result<()> {
+        debug!(target: "transcode", "run ffmpeg with args: {:?}", args);
+
+        // ffmpeg is killed when the conversion is dropped
+        let output = Command::new(&self.ffmpeg)
+            .args(["-hide_banner", "-loglevel", "error", "-y"])
+            .args(args)
+            .kill_on_drop(true)
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            return Err(std::io::Error::other(format!(
+                "ffmpeg exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid_path(path: std::ffi::OsString) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("path is not valid utf-8: {:?}", path),
+    )
+}
+
+// the killed process is looked up in procfs
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use crate::audio::transcode::Transcoder;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    #[actix_web::test]
+    async fn dropped_conversion_kills_ffmpeg() {
+        let dir = std::env::temp_dir().join(format!("wacr-transcode-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let pid_path = dir.join("pid");
+        let bin = dir.join("ffmpeg");
+        std::fs::write(
+            &bin,
+            format!(
+                "#!/bin/sh\necho $$ > {}\nexec sleep 30\n",
+                pid_path.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let transcoder = Transcoder::new(bin);
+        let conversion = transcoder.to_pcm_wav(dir.join("in.ogg"), dir.join("out.wav"), 16000, 1);
+        assert!(
+            actix_web::rt::time::timeout(Duration::from_millis(500), conversion)
+                .await
+                .is_err()
+        );
+
+        let pid = std::fs::read_to_string(&pid_path).unwrap();
+        let stat = format!("/proc/{}/stat", pid.trim());
+        let mut killed = false;
+        for _ in 0..50 {
+            // killed process is gone or waits to be reaped as a zombie
+            killed = std::fs::read_to_string(&stat)
+                .map(|s| {
+                    s.rsplit(')')
+                        .next()
+                        .unwrap_or_default()
+                        .trim_start()
+                        .starts_with('Z')
+                })
+                .unwrap_or(true);
+            if killed {
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(killed);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+use crate::audio::transcode::Transcoder;
+use log::{debug, warn};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Recordings are analyzed as 16kHz mono PCM.
+const VAD_SAMPLE_RATE: u32 = 16000;
+const FRAME_DURATION: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, Copy)]
+pub struct VadConfig {
+    /// Frames quieter than this level in dBFS are silence
+    pub threshold: f32,
+    /// Silence kept around the speech, so words aren't cut
+    pub padding: Duration,
+    /// Longer silences between the speech are shortened to this duration
+    pub max_silence: Duration,
+}
+
+/// Energy based voice activity detection.
+/// Drops leading and trailing silence and shortens long pauses.
+/// Returns empty samples if there is no speech at all.
+pub fn trim_silence(samples: &[i16], sample_rate: u32, config: &VadConfig) -> Vec<i16> {
+    let frame_len = (sample_rate as f64 * FRAME_DURATION.as_secs_f64()) as usize;
+    let frames = samples.chunks(frame_len.max(1)).collect::<Vec<_>>();
+    let to_frames = |d: Duration| (d.as_secs_f64() / FRAME_DURATION.as_secs_f64()).round() as usize;
+    let padding = to_frames(config.padding);
+    let max_silence = to_frames(config.max_silence);
+
+    let loud = frames
+        .iter()
+        .map(|f| level(f) >= config.threshold)
+        .collect::<Vec<_>>();
+
+    // speech with its padding
+    let voiced = (0..frames.len())
+        .map(|i| {
+            let from = i.saturating_sub(padding);
+            let to = (i + padding + 1).min(frames.len());
+            loud[from..to].iter().any(|&l| l)
+        })
+        .collect::<Vec<_>>();
+
+    let (first, last) = match (
+        voiced.iter().position(|&v| v),
+        voiced.iter().rposition(|&v| v),
+    ) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return vec![],
+    };
+
+    let mut trimmed = Vec::with_capacity(samples.len());
+    let mut silence = 0;
+    for (frame, voiced) in frames[first..=last].iter().zip(&voiced[first..=last]) {
+        silence = if *voiced { 0 } else { silence + 1 };
+        if silence <= max_silence {
+            trimmed.extend_from_slice(frame);
+        }
+    }
+    trimmed
+}
+
+/// RMS level of the frame in dBFS.
+fn level(frame: &[i16]) -> f32 {
+    let power = frame.iter().map(|&s| (s as f64).powi(2)).sum::<f64>() / frame.len() as f64;
+    (10.0 * (power / (i16::MAX as f64).powi(2)).log10()) as f32
+}
+
+/// Trims silence of the recording before it's recognized.
+#[derive(Debug, Clone)]
+pub struct VoiceTrimmer {
+    transcoder: Transcoder,
+    config: VadConfig,
+}
+
+impl VoiceTrimmer {
+    pub fn new(transcoder: Transcoder, config: VadConfig) -> Self {
+        Self { transcoder, config }
+    }
+
+    /// Writes the speech of the recording to a new WAV file.
+    /// Returns `None` if the recording has no speech.
+    pub async fn trim(&self, input: PathBuf) -> std::io::Result<Option<PathBuf>> {
+        let decoded = input.with_extension("vad.wav");
+        let output = input.with_extension("trimmed.wav");
+
+        self.transcoder
+            .to_pcm_wav(input, decoded.clone(), VAD_SAMPLE_RATE, 1)
+            .await?;
+
+        let config = self.config;
+        actix_web::web::block(move || {
+            let samples = hound::WavReader::open(&decoded)
+                .and_then(|r| r.into_samples::<i16>().collect::<Result<Vec<_>, _>>())
+                .map_err(std::io::Error::other);
+
+            if let Err(e) = std::fs::remove_file(&decoded) {
+                warn!(target: "vad", "fail to remove decoded audio {:?}: {}", decoded, e);
+            }
+
+            let samples = samples?;
+            let trimmed = trim_silence(&samples, VAD_SAMPLE_RATE, &config);
+            debug!(
+                target: "vad",
+                "trimmed {} of {} samples",
+                samples.len() - trimmed.len(),
+                samples.len()
+            );
+
+            if trimmed.is_empty() {
+                return Ok(None);
+            }
+
+            let mut writer = hound::WavWriter::create(
+                &output,
+                hound::WavSpec {
+                    channels: 1,
+                    sample_rate: VAD_SAMPLE_RATE,
+                    bits_per_sample: 16,
+                    sample_format: hound::SampleFormat::Int,
+                },
+            )
+            .map_err(std::io::Error::other)?;
+            trimmed
+                .into_iter()
+                .try_for_each(|s| writer.write_sample(s))
+                .and_then(|_| writer.finalize())
+                .map_err(std::io::Error::other)?;
+
+            Ok(Some(output))
+        })
+        .await
+        .map_err(std::io::Error::other)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audio::vad::{trim_silence, VadConfig};
+    use std::time::Duration;
+
+    const RATE: u32 = 1000;
+
+    fn config() -> VadConfig {
+        VadConfig {
+            threshold: -40.0,
+            padding: Duration::from_millis(40),
+            max_silence: Duration::from_millis(100),
+        }
+    }
+
+    /// 20 samples per 20ms frame at 1kHz
+    fn frames(count: usize, amplitude: i16) -> Vec<i16> {
+        (0..count * 20)
+            .map(|i| if i % 2 == 0 { amplitude } else { -amplitude })
+            .collect()
+    }
+
+    #[test]
+    fn silence_is_trimmed() {
+        let samples = [
+            frames(50, 0),
+            frames(10, 10000),
+            frames(30, 10),
+            frames(10, 10000),
+            frames(50, 0),
+        ]
+        .concat();
+
+        let trimmed = trim_silence(&samples, RATE, &config());
+
+        // 2 frames of padding around the speech, the rest of the pause is shortened to 5 frames
+        assert_eq!(trimmed.len(), (2 + 10 + 2 + 5 + 2 + 10 + 2) * 20);
+        assert_eq!(&trimmed[40..60], &frames(1, 10000)[..]);
+    }
+
+    #[test]
+    fn no_speech() {
+        assert!(trim_silence(&frames(100, 10), RATE, &config()).is_empty());
+    }
+}
@@ -0,0 +1,91 @@
+use crate::events::SessionEvent;
+use actix::prelude::*;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use log::trace;
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// How many events of a session are kept for late subscribers.
+const HISTORY_LIMIT: usize = 128;
+
+/// Delivers session events to subscribers.
+/// Subscriber receives all previous events of the session first, so it doesn't miss anything
+/// happened before it was connected.
+#[derive(Default)]
+pub struct EventBroker {
+    history: HashMap<Uuid, Vec<Arc<SessionEvent>>>,
+    subscribers: HashMap<Uuid, Vec<UnboundedSender<Arc<SessionEvent>>>>,
+}
+
+impl EventBroker {
+    pub fn new() -> Addr<Self> {
+        Self::default().start()
+    }
+}
+
+impl Actor for EventBroker {
+    type Context = Context<Self>;
+}
+
+impl Handler<Publish> for EventBroker {
+    type Result = ();
+
+    fn handle(&mut self, Publish(session_id, event): Publish, _ctx: &mut Self::Context) {
+        trace!(target: "events", "publish event {} -> {:?}", session_id, event);
+        let event = Arc::new(event);
+
+        let history = self.history.entry(session_id).or_default();
+        if history.len() >= HISTORY_LIMIT {
+            history.remove(0);
+        }
+        history.push(event.clone());
+
+        if let Some(subscribers) = self.subscribers.get_mut(&session_id) {
+            subscribers.retain(|s| s.unbounded_send(event.clone()).is_ok());
+        }
+    }
+}
+
+impl Handler<Subscribe> for EventBroker {
+    type Result = MessageResult<Subscribe>;
+
+    fn handle(
+        &mut self,
+        Subscribe(session_id): Subscribe,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let (tx, rx) = unbounded();
+
+        for event in self.history.get(&session_id).into_iter().flatten() {
+            let _ = tx.unbounded_send(event.clone());
+        }
+        self.subscribers.entry(session_id).or_default().push(tx);
+
+        MessageResult(Subscription(rx))
+    }
+}
+
+impl Handler<ClearEvents> for EventBroker {
+    type Result = ();
+
+    fn handle(&mut self, ClearEvents(session_id): ClearEvents, _ctx: &mut Self::Context) {
+        self.history.remove(&session_id);
+        self.subscribers.remove(&session_id);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Publish(pub Uuid, pub SessionEvent);
+
+#[derive(Message)]
+#[rtype(result = "Subscription")]
+pub struct Subscribe(pub Uuid);
+
+pub struct Subscription(pub UnboundedReceiver<Arc<SessionEvent>>);
+
+/// Drops history and closes streams of the session.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ClearEvents(pub Uuid);
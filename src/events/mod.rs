@@ -0,0 +1,92 @@
+use crate::asr::error::AsrError;
+use crate::webrtc::jitter::JitterStats;
+use crate::webrtc::writer::AudioCodec;
+use crate::webrtc::CloseReason;
+use serde::Serialize;
+use uuid::Uuid;
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+
+pub mod broker;
+
+/// Lifecycle events of the webrtc session and its speech recognition.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum SessionEvent {
+    IceStateChanged {
+        state: String,
+    },
+    /// Candidate gathered by the server, it's added to the client peer connection when ICE is trickled
+    IceCandidate {
+        candidate: RTCIceCandidateInit,
+    },
+    IceGatheringComplete,
+    /// Remote track is received, it's recorded to its own file
+    TrackAdded {
+        track: usize,
+        codec: AudioCodec,
+        channels: u8,
+        ssrc: u32,
+    },
+    FirstPacket,
+    RecordingPaused,
+    RecordingResumed,
+    SessionClosed {
+        reason: CloseReason,
+        /// Loss and reordering statistics of each track
+        tracks: Vec<JitterStats>,
+    },
+    AsrUploadStarted {
+        track: usize,
+    },
+    AsrTaskAssigned {
+        track: usize,
+        task_id: Uuid,
+    },
+    AsrProcessing {
+        track: usize,
+    },
+    /// Text of the segment recognized while the session is writing
+    AsrPartial {
+        track: usize,
+        segment: usize,
+        text: String,
+    },
+    AsrPartialFailed {
+        track: usize,
+        segment: usize,
+        #[serde(flatten)]
+        error: AsrError,
+    },
+    AsrFinished {
+        track: usize,
+        text: String,
+    },
+    AsrFailed {
+        track: usize,
+        #[serde(flatten)]
+        error: AsrError,
+    },
+}
+
+impl SessionEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SessionEvent::IceStateChanged { .. } => "ice_state_changed",
+            SessionEvent::IceCandidate { .. } => "ice_candidate",
+            SessionEvent::IceGatheringComplete => "ice_gathering_complete",
+            SessionEvent::TrackAdded { .. } => "track_added",
+            SessionEvent::FirstPacket => "first_packet",
+            SessionEvent::RecordingPaused => "recording_paused",
+            SessionEvent::RecordingResumed => "recording_resumed",
+            SessionEvent::SessionClosed { .. } => "session_closed",
+            SessionEvent::AsrUploadStarted { .. } => "asr_upload_started",
+            SessionEvent::AsrTaskAssigned { .. } => "asr_task_assigned",
+            SessionEvent::AsrProcessing { .. } => "asr_processing",
+            SessionEvent::AsrPartial { .. } => "asr_partial",
+            SessionEvent::AsrPartialFailed { .. } => "asr_partial_failed",
+            SessionEvent::AsrFinished { .. } => "asr_finished",
+            SessionEvent::AsrFailed { .. } => "asr_failed",
+        }
+    }
+}
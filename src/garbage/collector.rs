@@ -1,4 +1,5 @@
-use crate::webrtc::get_audio_path;
+use crate::events::broker::{ClearEvents, EventBroker};
+use crate::webrtc::quota::{session_files, StorageQuota};
 use crate::{UserAsrProcessorStorage, UserId, UserSessionStorage};
 use actix::prelude::*;
 use log::{error, info};
@@ -11,6 +12,8 @@ use uuid::Uuid;
 pub struct GarbageCollector {
     user_session_storage: Arc<UserSessionStorage>,
     user_asr_processor_storage: Arc<UserAsrProcessorStorage>,
+    events: Arc<Addr<EventBroker>>,
+    quota: Arc<StorageQuota>,
     dir: PathBuf,
     objects_ttl: u64,
 }
@@ -19,12 +22,16 @@ impl GarbageCollector {
     pub fn new(
         user_session_storage: Arc<UserSessionStorage>,
         user_asr_processor_storage: Arc<UserAsrProcessorStorage>,
+        events: Arc<Addr<EventBroker>>,
+        quota: Arc<StorageQuota>,
         dir: PathBuf,
         objects_ttl: u64,
     ) -> Addr<Self> {
         Self::create(|_| Self {
             user_session_storage,
             user_asr_processor_storage,
+            events,
+            quota,
             dir,
             objects_ttl,
         })
@@ -44,29 +51,56 @@ impl Handler<ClearSession> for GarbageCollector {
         ctx: &mut Self::Context,
     ) -> Self::Result {
         ctx.run_later(Duration::from_secs(self.objects_ttl), move |s, _ctx| {
+            s.events.do_send(ClearEvents(session_id));
             if let Some(session_storage) = s.user_session_storage.get(&user_id) {
                 info!(target: "garbage_collector", "clearing session from storage {} -> {}", user_id.0, session_id);
                 session_storage.remove(&session_id);
-                if let Err(e) = remove_file(get_audio_path(session_id,s.dir.clone() )) {
-                    error!(target: "garbage_collector", "fail to clear audio file {} from filesystem: {}", session_id, e)
-                }
+                s.clear_recordings(session_id);
             }
         });
     }
 }
 
+impl Handler<ClearRecordings> for GarbageCollector {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        ClearRecordings(session_id): ClearRecordings,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        ctx.run_later(Duration::from_secs(self.objects_ttl), move |s, _ctx| {
+            info!(target: "garbage_collector", "clearing recordings of previous run {}", session_id);
+            s.clear_recordings(session_id);
+        });
+    }
+}
+
+impl GarbageCollector {
+    fn clear_recordings(&self, session_id: Uuid) {
+        for path in session_files(&self.dir, session_id) {
+            if let Err(e) = remove_file(path) {
+                error!(target: "garbage_collector", "fail to clear audio file {} from filesystem: {}", session_id, e)
+            }
+        }
+        self.quota.release(session_id);
+    }
+}
+
 impl Handler<ClearAsr> for GarbageCollector {
     type Result = ();
 
     fn handle(
         &mut self,
-        ClearAsr(user_id, session_id): ClearAsr,
+        ClearAsr(user_id, session_id, track): ClearAsr,
         ctx: &mut Self::Context,
     ) -> Self::Result {
         ctx.run_later(Duration::from_secs(self.objects_ttl), move |s, _ctx| {
+            // events of the recognition finished after the session was cleared recreate its history
+            s.events.do_send(ClearEvents(session_id));
             if let Some(asr_processor_storage) = s.user_asr_processor_storage.get(&user_id) {
-                info!(target: "garbage_collector", "clearing asr from storage {} -> {}", user_id.0, session_id);
-                asr_processor_storage.remove(&session_id);
+                info!(target: "garbage_collector", "clearing asr from storage {} -> {} ({})", user_id.0, session_id, track);
+                asr_processor_storage.remove(&(session_id, track));
             }
         });
     }
@@ -76,6 +110,11 @@ impl Handler<ClearAsr> for GarbageCollector {
 #[rtype(result = "()")]
 pub struct ClearSession(pub UserId, pub Uuid);
 
+/// Recordings stored by the previous run, they're removed after the objects ttl since the start.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ClearRecordings(pub Uuid);
+
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct ClearAsr(pub UserId, pub Uuid);
+pub struct ClearAsr(pub UserId, pub Uuid, pub usize);
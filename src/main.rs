@@ -1,15 +1,38 @@
 mod api;
 mod asr;
+mod audio;
+mod events;
 mod garbage;
+mod webhook;
 mod webrtc;
 
-use crate::api::asr::api_text_to_speech;
+use crate::api::asr::{api_get_asr_state, api_text_to_speech};
+use crate::api::events::api_session_events;
+use crate::api::ice::{api_add_candidate, api_get_candidates, api_get_ice_servers};
 use crate::api::jwt::{generate_vk_jwt_method, jwt_token_guard, JwtConfig, UserId};
-use crate::api::session::{api_create_session, api_get_audio, SessionConfig};
+use crate::api::session::{
+    api_create_session, api_get_audio, api_get_tracks, api_pause_session, api_renegotiate_session,
+    api_resume_session, SessionConfig,
+};
+use crate::api::whip::{api_whip_create, api_whip_delete, whip_unauthorized};
+use crate::asr::backoff::BackoffConfig;
 use crate::asr::client::VkApi;
+use crate::asr::launcher::AsrLauncher;
 use crate::asr::processor::AsrProcessor;
-use crate::asr::AsrProcessorStorage;
-use crate::garbage::collector::GarbageCollector;
+use crate::asr::recognizer::SpeechRecognizer;
+use crate::asr::retry::{CircuitBreaker, RetryPolicy};
+use crate::asr::vk::VkRecognizer;
+use crate::asr::whisper::{WhisperConfig, WhisperRecognizer};
+use crate::asr::{AsrBackend, AsrConfig, AsrProcessorStorage};
+use crate::audio::transcode::Transcoder;
+use crate::audio::vad::{VadConfig, VoiceTrimmer};
+use crate::events::broker::EventBroker;
+use crate::garbage::collector::{ClearRecordings, GarbageCollector};
+use crate::webhook::dispatcher::{WebhookConfig, WebhookDispatcher};
+use crate::webrtc::ice::{IceConfig, TurnAuth};
+use crate::webrtc::network::{NatCandidate, NetworkConfig};
+use crate::webrtc::quota::{QuotaLimits, StorageQuota};
+use crate::webrtc::segmenter::SegmentConfig;
 use crate::webrtc::{create_api, PortRange, SessionStorage};
 use actix_files::Files;
 use actix_web::http::{header, Method};
@@ -22,7 +45,10 @@ use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
-use vkclient::upload::VkUploader;
+use url::Url;
+
+/// Free space of `AUDIO_DIR` is measured with this interval when `RECORDING_MIN_FREE_BYTES` is set
+const FREE_SPACE_INTERVAL: Duration = Duration::from_secs(10);
 
 pub type UserSessionStorage = DashMap<UserId, Arc<SessionStorage>>;
 pub type UserAsrProcessorStorage = DashMap<UserId, Arc<AsrProcessorStorage>>;
@@ -53,6 +79,63 @@ async fn main() -> std::io::Result<()> {
         .map(Duration::from_secs)
         .expect("session total timeout is invalid");
 
+    let session_ice_grace_period = std::env::var("SESSION_ICE_GRACE_PERIOD")
+        .unwrap_or_else(|_| "10".to_string())
+        .parse()
+        .map(Duration::from_secs)
+        .expect("session ice grace period is invalid");
+
+    let live_segment_max_duration = std::env::var("LIVE_SEGMENT_MAX_DURATION")
+        .unwrap_or_else(|_| "5".to_string())
+        .parse()
+        .map(Duration::from_secs_f64)
+        .expect("live segment max duration is invalid");
+
+    let live_segment_silence = std::env::var("LIVE_SEGMENT_SILENCE")
+        .unwrap_or_else(|_| "0.6".to_string())
+        .parse()
+        .map(Duration::from_secs_f64)
+        .expect("live segment silence is invalid");
+
+    let live_segment_threshold = std::env::var("LIVE_SEGMENT_THRESHOLD")
+        .unwrap_or_else(|_| "-45".to_string())
+        .parse()
+        .expect("live segment threshold is invalid");
+
+    let jitter_buffer = std::env::var("JITTER_BUFFER_PACKETS")
+        .unwrap_or_else(|_| "5".to_string())
+        .parse()
+        .expect("jitter buffer packets is invalid");
+
+    let audio_path =
+        PathBuf::from(std::env::var("AUDIO_DIR").unwrap_or_else(|_| "/tmp".to_string()));
+
+    let quota = Arc::new(StorageQuota::new(
+        QuotaLimits {
+            session: std::env::var("RECORDING_MAX_BYTES_SESSION")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .map(|v| {
+                    v.parse()
+                        .expect("recording max bytes of session is invalid")
+                }),
+            user: std::env::var("RECORDING_MAX_BYTES_USER")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .map(|v| v.parse().expect("recording max bytes of user is invalid")),
+            total: std::env::var("RECORDING_MAX_BYTES_TOTAL")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .map(|v| v.parse().expect("recording max bytes total is invalid")),
+            min_free: std::env::var("RECORDING_MIN_FREE_BYTES")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .map(|v| v.parse().expect("recording min free bytes is invalid")),
+        },
+        audio_path.clone(),
+    ));
+    let stored_sessions = quota.seed();
+
     let garbage_collector_ttl = std::env::var("GARBAGE_COLLECTOR_TTL")
         .unwrap_or_else(|_| "3600".to_string())
         .parse()
@@ -77,17 +160,189 @@ async fn main() -> std::io::Result<()> {
     )
     .filter(|i| !i.is_empty());
 
+    let network = NetworkConfig {
+        interfaces: interfaces_allowed,
+        family: std::env::var("WEBRTC_IP_FAMILY")
+            .unwrap_or_else(|_| "any".to_string())
+            .parse()
+            .expect("webrtc ip family is invalid"),
+        networks: std::env::var("WEBRTC_NETWORKS_ALLOWED")
+            .unwrap_or_else(|_| "".to_string())
+            .split(',')
+            .filter(|n| !n.is_empty())
+            .map(|n| n.trim().parse().expect("webrtc allowed network is invalid"))
+            .collect(),
+        nat_1to1_ips: std::env::var("WEBRTC_NAT_1TO1_IPS")
+            .unwrap_or_else(|_| "".to_string())
+            .split(',')
+            .filter(|ip| !ip.is_empty())
+            .map(|ip| ip.trim().parse().expect("webrtc nat 1to1 ip is invalid"))
+            .collect(),
+        nat_1to1_candidate: std::env::var("WEBRTC_NAT_1TO1_CANDIDATE")
+            .unwrap_or_else(|_| "host".to_string())
+            .parse()
+            .expect("webrtc nat 1to1 candidate is invalid"),
+        udp_mux: std::env::var("WEBRTC_UDP_MUX_ADDRESS")
+            .ok()
+            .filter(|a| !a.is_empty())
+            .map(|a| a.parse().expect("webrtc udp mux address is invalid")),
+    };
+
+    // empty list disables the servers
+    let ice_urls = |name, default: &str| {
+        std::env::var(name)
+            .unwrap_or_else(|_| default.to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|u| !u.is_empty())
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+    };
+
+    let ice = Arc::new(IceConfig {
+        stun: ice_urls("WEBRTC_STUN_URLS", "stun:stun.l.google.com:19302"),
+        turn: ice_urls("WEBRTC_TURN_URLS", ""),
+        turn_auth: match (
+            std::env::var("WEBRTC_TURN_SECRET").ok(),
+            std::env::var("WEBRTC_TURN_USERNAME").ok(),
+        ) {
+            (Some(secret), _) => Some(TurnAuth::Secret {
+                secret,
+                ttl: std::env::var("WEBRTC_TURN_TTL")
+                    .unwrap_or_else(|_| "86400".to_string())
+                    .parse()
+                    .map(Duration::from_secs)
+                    .expect("webrtc turn ttl is invalid"),
+            }),
+            (None, Some(username)) => Some(TurnAuth::Static {
+                username,
+                credential: std::env::var("WEBRTC_TURN_CREDENTIAL")
+                    .expect("missed env WEBRTC_TURN_CREDENTIAL"),
+            }),
+            (None, None) => None,
+        },
+    });
+    assert!(
+        ice.turn.is_empty() || ice.turn_auth.is_some(),
+        "missed env WEBRTC_TURN_SECRET or WEBRTC_TURN_USERNAME"
+    );
+    // public address is the server reflexive candidate already
+    assert!(
+        network.nat_1to1_ips.is_empty()
+            || network.nat_1to1_candidate == NatCandidate::Host
+            || ice.stun.is_empty(),
+        "WEBRTC_STUN_URLS must be empty when WEBRTC_NAT_1TO1_CANDIDATE is srflx"
+    );
+
     let static_dir = std::env::var("STATIC_DIR").ok();
 
-    let service_token = std::env::var("VK_API_SERVICE_TOKEN").expect("missed env SERVICE_TOKEN");
+    let asr_deadline = std::env::var("ASR_DEADLINE")
+        .unwrap_or_else(|_| "300".to_string())
+        .parse()
+        .map(Duration::from_secs)
+        .expect("asr deadline is invalid");
+
+    let asr_backend = std::env::var("ASR_BACKEND")
+        .unwrap_or_else(|_| "vk".to_string())
+        .parse::<AsrBackend>()
+        .expect("asr backend is invalid");
+
+    let ffmpeg_bin =
+        PathBuf::from(std::env::var("FFMPEG_BIN").unwrap_or_else(|_| "ffmpeg".to_string()));
+
     let service_key = std::env::var("VK_API_SERVICE_KEY").expect("missed env SERVICE_KEY");
-    let audio_path =
-        PathBuf::from(std::env::var("AUDIO_DIR").unwrap_or_else(|_| "/tmp".to_string()));
+    let transcoder = Transcoder::new(ffmpeg_bin);
+
+    let recognizer: Arc<dyn SpeechRecognizer> = match asr_backend {
+        AsrBackend::Vk => {
+            let service_token =
+                std::env::var("VK_API_SERVICE_TOKEN").expect("missed env SERVICE_TOKEN");
+
+            let vk_api_url = std::env::var("VK_API_URL")
+                .unwrap_or_else(|_| "https://api.vk.com".to_string())
+                .parse::<Url>()
+                .expect("vk api url is invalid");
+
+            let polling = BackoffConfig {
+                initial: std::env::var("ASR_POLL_INTERVAL")
+                    .unwrap_or_else(|_| "1".to_string())
+                    .parse()
+                    .map(Duration::from_secs_f64)
+                    .expect("asr poll interval is invalid"),
+                max: std::env::var("ASR_POLL_MAX_INTERVAL")
+                    .unwrap_or_else(|_| "10".to_string())
+                    .parse()
+                    .map(Duration::from_secs_f64)
+                    .expect("asr poll max interval is invalid"),
+                multiplier: std::env::var("ASR_POLL_MULTIPLIER")
+                    .unwrap_or_else(|_| "1.5".to_string())
+                    .parse()
+                    .expect("asr poll multiplier is invalid"),
+            };
 
-    let vk_client = web::Data::new(VkApi::new(service_token.clone()));
-    let vk_client_uploader = web::Data::new(VkUploader::default());
+            let retry = RetryPolicy {
+                attempts: std::env::var("VK_API_RETRY_ATTEMPTS")
+                    .unwrap_or_else(|_| "3".to_string())
+                    .parse()
+                    .expect("vk api retry attempts is invalid"),
+                backoff: BackoffConfig {
+                    initial: std::env::var("VK_API_RETRY_DELAY")
+                        .unwrap_or_else(|_| "0.5".to_string())
+                        .parse()
+                        .map(Duration::from_secs_f64)
+                        .expect("vk api retry delay is invalid"),
+                    max: std::env::var("VK_API_RETRY_MAX_DELAY")
+                        .unwrap_or_else(|_| "5".to_string())
+                        .parse()
+                        .map(Duration::from_secs_f64)
+                        .expect("vk api retry max delay is invalid"),
+                    multiplier: 2.0,
+                },
+                timeout: std::env::var("VK_API_REQUEST_TIMEOUT")
+                    .unwrap_or_else(|_| "30".to_string())
+                    .parse()
+                    .map(Duration::from_secs)
+                    .expect("vk api request timeout is invalid"),
+            };
+
+            let breaker = CircuitBreaker::new(
+                std::env::var("VK_API_BREAKER_THRESHOLD")
+                    .unwrap_or_else(|_| "5".to_string())
+                    .parse()
+                    .expect("vk api breaker threshold is invalid"),
+                std::env::var("VK_API_BREAKER_COOLDOWN")
+                    .unwrap_or_else(|_| "30".to_string())
+                    .parse()
+                    .map(Duration::from_secs)
+                    .expect("vk api breaker cooldown is invalid"),
+            );
+
+            Arc::new(VkRecognizer::new(
+                VkApi::new(service_token, vk_api_url),
+                polling,
+                retry,
+                breaker,
+            ))
+        }
+        AsrBackend::Whisper => Arc::new(WhisperRecognizer::new(
+            WhisperConfig {
+                bin: PathBuf::from(
+                    std::env::var("WHISPER_BIN").unwrap_or_else(|_| "whisper-cli".to_string()),
+                ),
+                model: PathBuf::from(
+                    std::env::var("WHISPER_MODEL").expect("missed env WHISPER_MODEL"),
+                ),
+                language: std::env::var("WHISPER_LANGUAGE").unwrap_or_else(|_| "auto".to_string()),
+                threads: std::env::var("WHISPER_THREADS")
+                    .unwrap_or_else(|_| "4".to_string())
+                    .parse()
+                    .expect("whisper threads is invalid"),
+            },
+            transcoder.clone(),
+        )),
+    };
     let web_rtc_api = web::Data::new(
-        create_api(PortRange(udp_port_min, udp_port_max), interfaces_allowed)
+        create_api(PortRange(udp_port_min, udp_port_max), network)
             .expect("fail to create api instance"),
     );
     let user_session_storage = web::Data::new(UserSessionStorage::new());
@@ -97,50 +352,167 @@ async fn main() -> std::io::Result<()> {
         dir: audio_path,
         timeout: session_timeout,
         total_timeout: session_total_timeout,
+        ice_grace_period: session_ice_grace_period,
+        segment: SegmentConfig {
+            max_duration: live_segment_max_duration,
+            silence: live_segment_silence,
+            threshold: live_segment_threshold,
+        },
+        transcoder: transcoder.clone(),
+        jitter_buffer,
+        quota: quota.clone(),
+        ice,
+    });
+
+    let asr_vad_enabled = std::env::var("ASR_VAD_ENABLED")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse::<bool>()
+        .expect("asr vad enabled is invalid");
+
+    let asr_config = AsrConfig {
+        deadline: asr_deadline,
+        trimmer: asr_vad_enabled.then(|| {
+            VoiceTrimmer::new(
+                transcoder.clone(),
+                VadConfig {
+                    threshold: std::env::var("ASR_VAD_THRESHOLD")
+                        .unwrap_or_else(|_| "-45".to_string())
+                        .parse()
+                        .expect("asr vad threshold is invalid"),
+                    padding: std::env::var("ASR_VAD_PADDING")
+                        .unwrap_or_else(|_| "0.3".to_string())
+                        .parse()
+                        .map(Duration::from_secs_f64)
+                        .expect("asr vad padding is invalid"),
+                    max_silence: std::env::var("ASR_VAD_MAX_SILENCE")
+                        .unwrap_or_else(|_| "1".to_string())
+                        .parse()
+                        .map(Duration::from_secs_f64)
+                        .expect("asr vad max silence is invalid"),
+                },
+            )
+        }),
+    };
+
+    let webhook_secret = std::env::var("WEBHOOK_SECRET").ok();
+    let webhook_url = std::env::var("WEBHOOK_URL").ok().map(|u| {
+        assert!(webhook_secret.is_some(), "missed env WEBHOOK_SECRET");
+        u.parse::<Url>().expect("webhook url is invalid")
     });
 
+    let webhook_config = web::Data::new(WebhookConfig {
+        url: webhook_url,
+        secret: webhook_secret,
+        allowed_hosts: std::env::var("WEBHOOK_ALLOWED_HOSTS")
+            .ok()
+            .filter(|h| !h.is_empty())
+            .map(|h| {
+                h.split(',')
+                    .map(|h| h.trim().to_ascii_lowercase())
+                    .collect::<HashSet<_>>()
+            }),
+        dir: std::env::var("WEBHOOK_QUEUE_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| config.dir.join("webhooks")),
+        attempts: std::env::var("WEBHOOK_RETRY_ATTEMPTS")
+            .unwrap_or_else(|_| "10".to_string())
+            .parse()
+            .expect("webhook retry attempts is invalid"),
+        backoff: BackoffConfig {
+            initial: std::env::var("WEBHOOK_RETRY_DELAY")
+                .unwrap_or_else(|_| "5".to_string())
+                .parse()
+                .map(Duration::from_secs)
+                .expect("webhook retry delay is invalid"),
+            max: std::env::var("WEBHOOK_RETRY_MAX_DELAY")
+                .unwrap_or_else(|_| "600".to_string())
+                .parse()
+                .map(Duration::from_secs)
+                .expect("webhook retry max delay is invalid"),
+            multiplier: 2.0,
+        },
+    });
+
+    let webhook_dispatcher = Arc::new(WebhookDispatcher::new(WebhookConfig::clone(
+        &webhook_config,
+    )));
+
     let jwt_config = web::Data::new(JwtConfig {
         service_key,
         expiration: jwt_expiration,
     });
 
+    let events = web::Data::new(EventBroker::new());
+
     let garbage_collector = web::Data::new(GarbageCollector::new(
         user_session_storage.clone().into_inner(),
         user_asr_processor_storage.clone().into_inner(),
+        events.clone().into_inner(),
+        quota.clone(),
         config.dir.clone(),
         garbage_collector_ttl,
     ));
+    for session_id in stored_sessions {
+        garbage_collector.do_send(ClearRecordings(session_id));
+    }
+    actix_web::rt::spawn(quota.watch_free_space(FREE_SPACE_INTERVAL));
+
+    let asr_launcher = web::Data::new(AsrLauncher {
+        storage: user_asr_processor_storage.clone().into_inner(),
+        recognizer,
+        config: asr_config,
+        dir: config.dir.clone(),
+        callback: webhook_config.url.clone(),
+        garbage_collector: garbage_collector.clone().into_inner(),
+        webhook_dispatcher,
+        events: events.clone().into_inner(),
+    });
 
     HttpServer::new(move || {
         App::new()
             .wrap(
                 actix_cors::Cors::default()
-                    .allowed_methods([Method::GET, Method::POST])
+                    .allowed_methods([Method::GET, Method::POST, Method::DELETE])
                     .allowed_headers([
                         header::AUTHORIZATION,
                         header::ACCEPT,
                         header::ACCEPT_ENCODING,
                         header::CONTENT_TYPE,
                     ])
+                    // resource of the WHIP session
+                    .expose_headers([header::LOCATION])
                     .allow_any_origin()
                     .max_age(3600),
             )
             .wrap(Compress::default())
-            .app_data(vk_client.clone())
-            .app_data(vk_client_uploader.clone())
             .app_data(web_rtc_api.clone())
             .app_data(user_session_storage.clone())
             .app_data(user_asr_processor_storage.clone())
             .app_data(config.clone())
             .app_data(jwt_config.clone())
             .app_data(garbage_collector.clone())
+            .app_data(webhook_config.clone())
+            .app_data(asr_launcher.clone())
+            .app_data(events.clone())
             .service(
                 scope("/session")
                     .guard(jwt_token_guard(jwt_config.service_key.clone()))
                     .service(api_create_session)
                     .service(api_get_audio)
-                    .service(api_text_to_speech),
+                    .service(api_get_tracks)
+                    .service(api_pause_session)
+                    .service(api_resume_session)
+                    .service(api_renegotiate_session)
+                    .service(api_whip_create)
+                    .service(api_whip_delete)
+                    .service(api_get_ice_servers)
+                    .service(api_add_candidate)
+                    .service(api_get_candidates)
+                    .service(api_text_to_speech)
+                    .service(api_get_asr_state)
+                    .service(api_session_events),
             )
+            .service(whip_unauthorized("/session"))
             .configure(|sc| {
                 if let Some(p) = static_dir.clone() {
                     sc.service(
@@ -0,0 +1,564 @@
+use crate::asr::backoff::BackoffConfig;
+use actix::prelude::*;
+use futures::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::Service;
+use hyper::{Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::fs::{read_dir, remove_file, rename, File};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use url::{Host, Url};
+use uuid::Uuid;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+pub const SIGNATURE_HEADER: &str = "X-Wacr-Signature";
+pub const DELIVERY_HEADER: &str = "X-Wacr-Delivery";
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// Callback used when the client didn't pass its own
+    pub url: Option<Url>,
+    /// Secret of HMAC signature, webhooks are disabled without it
+    pub secret: Option<String>,
+    /// Hosts of callbacks passed by clients, `None` allows any host with public addresses
+    pub allowed_hosts: Option<HashSet<String>>,
+    /// Directory of not delivered webhooks, they are retried after restart
+    pub dir: PathBuf,
+    pub attempts: usize,
+    pub backoff: BackoffConfig,
+}
+
+/// Delivers webhooks with retries.
+/// Every webhook is stored to the queue directory until it's delivered or attempts are over.
+pub struct WebhookDispatcher {
+    /// Client of `WebhookConfig::url` and `WebhookConfig::allowed_hosts`
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    /// Client of other callbacks passed by clients, it connects only to public addresses
+    public_client: Client<HttpsConnector<HttpConnector<PublicResolver>>, Body>,
+    config: WebhookConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WebhookDelivery {
+    id: Uuid,
+    url: Url,
+    body: String,
+    attempt: usize,
+}
+
+impl WebhookDispatcher {
+    pub fn new(config: WebhookConfig) -> Addr<Self> {
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .enable_http2()
+            .build();
+
+        let mut http = HttpConnector::new_with_resolver(PublicResolver(GaiResolver::new()));
+        http.enforce_http(false);
+        let public_https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .enable_http2()
+            .wrap_connector(http);
+
+        // queue is ready before the actor is started, webhooks can be stored right away
+        if let Err(e) = std::fs::create_dir_all(&config.dir) {
+            error!(target: "webhook", "fail to create webhooks queue {:?}: {}", config.dir, e);
+        }
+
+        Self::create(|_| Self {
+            client: Client::builder().build(https),
+            public_client: Client::builder().build(public_https),
+            config,
+        })
+    }
+
+    /// Callback is trusted when it's configured by the server, not passed by the client.
+    fn is_trusted(&self, url: &Url) -> bool {
+        self.config.url.as_ref() == Some(url)
+            || matches!(
+                (&self.config.allowed_hosts, url.host_str()),
+                (Some(allowed), Some(h)) if allowed.contains(&h.to_ascii_lowercase())
+            )
+    }
+
+    fn deliver(&self, delivery: WebhookDelivery, ctx: &mut Context<Self>) {
+        let request = match self.request(&delivery) {
+            Ok(r) => r,
+            Err(e) => {
+                error!(target: "webhook", "fail to build webhook {} request: {}", delivery.id, e);
+                self.remove(&delivery);
+                return;
+            }
+        };
+
+        let trusted = self.is_trusted(&delivery.url);
+        // addresses aren't resolved for ip hosts, so they're checked here
+        let ip = match delivery.url.host() {
+            Some(Host::Ipv4(ip)) => Some(IpAddr::V4(ip)),
+            Some(Host::Ipv6(ip)) => Some(IpAddr::V6(ip)),
+            _ => None,
+        };
+        if !trusted && ip.is_some_and(|ip| !is_public(&ip)) {
+            error!(target: "webhook", "webhook {} is dropped, {} isn't public", delivery.id, delivery.url);
+            self.remove(&delivery);
+            return;
+        }
+
+        let response = if trusted {
+            self.client.request(request)
+        } else {
+            self.public_client.request(request)
+        };
+
+        ctx.spawn(
+            async move {
+                let response = actix_web::rt::time::timeout(WEBHOOK_TIMEOUT, response)
+                    .await
+                    .map_err(|_| "request timed out".to_string())?
+                    .map_err(|e| e.to_string())?;
+
+                if response.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(format!("callback responded with status {}", response.status()))
+                }
+            }
+            .into_actor(self)
+            .map(move |result: Result<(), String>, act, ctx| match result {
+                Ok(()) => {
+                    debug!(target: "webhook", "webhook {} delivered to {}", delivery.id, delivery.url);
+                    act.remove(&delivery);
+                }
+                Err(e) => act.retry(delivery, e, ctx),
+            }),
+        );
+    }
+
+    fn retry(&self, mut delivery: WebhookDelivery, e: String, ctx: &mut Context<Self>) {
+        delivery.attempt += 1;
+
+        if delivery.attempt >= self.config.attempts {
+            error!(
+                target: "webhook",
+                "webhook {} to {} is dropped after {} attempts: {}",
+                delivery.id, delivery.url, delivery.attempt, e
+            );
+            self.remove(&delivery);
+            return;
+        }
+
+        let delay = self
+            .config
+            .backoff
+            .delays()
+            .nth(delivery.attempt - 1)
+            .unwrap_or(self.config.backoff.max);
+        warn!(
+            target: "webhook",
+            "webhook {} to {} failed, retry in {:?}: {}",
+            delivery.id, delivery.url, delay, e
+        );
+
+        self.store(&delivery);
+        ctx.run_later(delay, move |act, ctx| act.deliver(delivery, ctx));
+    }
+
+    fn request(&self, delivery: &WebhookDelivery) -> Result<Request<Body>, hyper::http::Error> {
+        let mut request = Request::post(delivery.url.as_str())
+            .header(CONTENT_TYPE, "application/json")
+            .header(DELIVERY_HEADER, delivery.id.to_string());
+
+        if let Some(secret) = &self.config.secret {
+            let timestamp = chrono::Utc::now().timestamp();
+            request = request.header(
+                SIGNATURE_HEADER,
+                format!(
+                    "t={},v1={}",
+                    timestamp,
+                    sign(secret, timestamp, &delivery.body)
+                ),
+            );
+        }
+
+        request.body(Body::from(delivery.body.clone()))
+    }
+
+    fn path(&self, id: Uuid) -> PathBuf {
+        self.config.dir.join(format!("{}.json", id))
+    }
+
+    fn store(&self, delivery: &WebhookDelivery) {
+        let path = self.path(delivery.id);
+        let tmp_path = path.with_extension("tmp");
+
+        let result = File::create(&tmp_path)
+            .and_then(|f| serde_json::to_writer(f, delivery).map_err(std::io::Error::other))
+            .and_then(|_| rename(&tmp_path, &path));
+
+        if let Err(e) = result {
+            error!(target: "webhook", "fail to store webhook {}: {}", delivery.id, e);
+        }
+    }
+
+    fn remove(&self, delivery: &WebhookDelivery) {
+        if let Err(e) = remove_file(self.path(delivery.id)) {
+            error!(target: "webhook", "fail to remove webhook {}: {}", delivery.id, e);
+        }
+    }
+
+    fn load(&self) -> std::io::Result<Vec<WebhookDelivery>> {
+        let mut deliveries = vec![];
+
+        for entry in read_dir(&self.config.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|e| e != "json") {
+                continue;
+            }
+
+            match File::open(&path).and_then(|f| {
+                serde_json::from_reader::<_, WebhookDelivery>(f).map_err(std::io::Error::other)
+            }) {
+                Ok(d) => deliveries.push(d),
+                Err(e) => error!(target: "webhook", "fail to load webhook {:?}: {}", path, e),
+            }
+        }
+
+        Ok(deliveries)
+    }
+}
+
+impl Actor for WebhookDispatcher {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        match self.load() {
+            Ok(deliveries) => {
+                if !deliveries.is_empty() {
+                    info!(target: "webhook", "restored {} webhooks from queue", deliveries.len());
+                }
+                for delivery in deliveries {
+                    self.deliver(delivery, ctx);
+                }
+            }
+            Err(e) => error!(target: "webhook", "fail to load webhooks queue: {}", e),
+        }
+    }
+}
+
+impl Handler<SendWebhook> for WebhookDispatcher {
+    type Result = ();
+
+    fn handle(&mut self, SendWebhook(url, body): SendWebhook, ctx: &mut Self::Context) {
+        let body = match serde_json::to_string(&body) {
+            Ok(b) => b,
+            Err(e) => {
+                error!(target: "webhook", "fail to serialize webhook to {}: {}", url, e);
+                return;
+            }
+        };
+
+        let delivery = WebhookDelivery {
+            id: Uuid::new_v4(),
+            url,
+            body,
+            attempt: 0,
+        };
+
+        self.store(&delivery);
+        self.deliver(delivery, ctx);
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of `{timestamp}.{body}`.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac: Hmac<Sha256> =
+        Hmac::new_from_slice(secret.as_bytes()).expect("hmac accepts key of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Callback passed by the client mustn't reach internal services of the server network,
+/// `WebhookConfig::url` is trusted and isn't checked.
+pub async fn check_callback(config: &WebhookConfig, url: &Url) -> Result<(), &'static str> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("callback url must be http or https");
+    }
+    let Some(host) = url.host() else {
+        return Err("callback url must have a host");
+    };
+
+    if let Some(allowed) = &config.allowed_hosts {
+        return match url.host_str() {
+            Some(h) if allowed.contains(&h.to_ascii_lowercase()) => Ok(()),
+            _ => Err("callback host isn't allowed"),
+        };
+    }
+
+    let addresses = match host {
+        Host::Ipv4(ip) => vec![IpAddr::V4(ip)],
+        Host::Ipv6(ip) => vec![IpAddr::V6(ip)],
+        Host::Domain(domain) => {
+            let target = (
+                domain.to_string(),
+                url.port_or_known_default().unwrap_or(80),
+            );
+            actix_web::web::block(move || target.to_socket_addrs())
+                .await
+                .ok()
+                .and_then(Result::ok)
+                .map(|a| a.map(|a| a.ip()).collect())
+                .unwrap_or_default()
+        }
+    };
+
+    if addresses.is_empty() {
+        return Err("callback host can't be resolved");
+    }
+    if !addresses.iter().all(is_public) {
+        return Err("callback host isn't public");
+    }
+    Ok(())
+}
+
+/// Resolves hosts of client callbacks, the connection fails when any address isn't public.
+/// Addresses are checked on every connection, so the host can't be rebound to an internal one after `check_callback`.
+#[derive(Clone)]
+struct PublicResolver(GaiResolver);
+
+impl Service<Name> for PublicResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = std::io::Error;
+    type Future = BoxFuture<'static, std::io::Result<Self::Response>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolving = self.0.call(name.clone());
+        Box::pin(async move {
+            let addresses = resolving.await?.collect::<Vec<_>>();
+            if addresses.iter().all(|a| is_public(&a.ip())) {
+                Ok(addresses.into_iter())
+            } else {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!("callback host {} isn't public", name),
+                ))
+            }
+        })
+    }
+}
+
+fn is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // shared address space of carrier-grade NAT
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xC0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(&IpAddr::V4(ip)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SendWebhook(pub Url, pub serde_json::Value);
+
+#[cfg(test)]
+mod tests {
+    use crate::asr::backoff::BackoffConfig;
+    use crate::webhook::dispatcher::{
+        sign, SendWebhook, WebhookConfig, WebhookDispatcher, SIGNATURE_HEADER,
+    };
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use url::Url;
+
+    #[test]
+    fn signature() {
+        assert_eq!(
+            sign("secret", 1664718489, r#"{"state":"finished"}"#),
+            "a0c36e13ecbffcbb7725b3969d5bb8d879cc2002a7de474d5ba8cc7df194e9ad"
+        );
+    }
+
+    #[actix_web::test]
+    async fn failed_delivery_is_retried_and_signed() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = futures::channel::oneshot::channel::<(String, String)>();
+        let tx = Arc::new(std::sync::Mutex::new(Some(tx)));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/callback",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let server = HttpServer::new({
+            let calls = calls.clone();
+            move || {
+                let calls = calls.clone();
+                let tx = tx.clone();
+                App::new().route(
+                    "/callback",
+                    web::post().to(move |req: HttpRequest, body: String| {
+                        let calls = calls.clone();
+                        let tx = tx.clone();
+                        async move {
+                            if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                                return HttpResponse::ServiceUnavailable().finish();
+                            }
+                            let signature = req
+                                .headers()
+                                .get(SIGNATURE_HEADER)
+                                .unwrap()
+                                .to_str()
+                                .unwrap();
+                            if let Some(tx) = tx.lock().unwrap().take() {
+                                let _ = tx.send((signature.to_string(), body));
+                            }
+                            HttpResponse::Ok().finish()
+                        }
+                    }),
+                )
+            }
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let dir = std::env::temp_dir().join(format!("wacr-webhooks-{}", uuid::Uuid::new_v4()));
+        let dispatcher = WebhookDispatcher::new(WebhookConfig {
+            url: Some(url.clone()),
+            secret: Some("secret".to_string()),
+            allowed_hosts: None,
+            dir: dir.clone(),
+            attempts: 3,
+            backoff: BackoffConfig {
+                initial: Duration::from_millis(10),
+                max: Duration::from_millis(10),
+                multiplier: 1.0,
+            },
+        });
+        dispatcher
+            .send(SendWebhook(url, json!({ "state": "finished" })))
+            .await
+            .unwrap();
+
+        let (signature, body) = rx.await.unwrap();
+        let (timestamp, hash) = signature
+            .strip_prefix("t=")
+            .and_then(|s| s.split_once(",v1="))
+            .unwrap();
+        assert_eq!(hash, sign("secret", timestamp.parse().unwrap(), &body));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        handle.stop(false).await;
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[actix_web::test]
+    async fn internal_callbacks_are_not_delivered() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = HttpServer::new({
+            let calls = calls.clone();
+            move || {
+                let calls = calls.clone();
+                App::new().default_service(web::to(move || {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    async { HttpResponse::Ok().finish() }
+                }))
+            }
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let dir = std::env::temp_dir().join(format!("wacr-webhooks-{}", uuid::Uuid::new_v4()));
+        let dispatcher = WebhookDispatcher::new(WebhookConfig {
+            url: None,
+            secret: Some("secret".to_string()),
+            allowed_hosts: None,
+            dir: dir.clone(),
+            attempts: 2,
+            backoff: BackoffConfig {
+                initial: Duration::from_millis(10),
+                max: Duration::from_millis(10),
+                multiplier: 1.0,
+            },
+        });
+        // host resolved to an internal address after the callback was checked
+        for url in [
+            format!("http://localhost:{}/callback", port),
+            format!("http://127.0.0.1:{}/callback", port),
+        ] {
+            dispatcher
+                .send(SendWebhook(
+                    Url::parse(&url).unwrap(),
+                    json!({ "state": "finished" }),
+                ))
+                .await
+                .unwrap();
+        }
+
+        for _ in 0..100 {
+            if std::fs::read_dir(&dir).unwrap().count() == 0 {
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        handle.stop(false).await;
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::asr::client::SpeechModel;
+use crate::events::SessionEvent;
+use actix_web::rt::task::JoinHandle;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use log::{trace, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
+use webrtc::data_channel::RTCDataChannel;
+
+/// Label of the control data channel, the client creates it with the same id,
+/// so it isn't announced in-band.
+pub const CONTROL_CHANNEL_LABEL: &str = "control";
+pub const CONTROL_CHANNEL_ID: u16 = 0;
+
+pub fn control_channel_init() -> RTCDataChannelInit {
+    RTCDataChannelInit {
+        ordered: Some(true),
+        negotiated: Some(CONTROL_CHANNEL_ID),
+        ..Default::default()
+    }
+}
+
+/// Commands sent by the client over the control channel.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum ControlRequest {
+    #[serde(alias = "start")]
+    Resume,
+    Pause,
+    Stop,
+    /// Session is closed and the track is recognized, the text is sent back before the channel is closed
+    Recognize {
+        speech: SpeechModel,
+        /// Number of the recorded track, the first one by default
+        #[serde(default)]
+        track: usize,
+    },
+    KeepAlive,
+}
+
+/// Messages sent by the server over the control channel.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum ControlNotification {
+    Event(SessionEvent),
+    Error(ControlError),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename = "error")]
+pub struct ControlError {
+    pub error: String,
+}
+
+impl ControlNotification {
+    pub fn error(error: impl ToString) -> Self {
+        ControlNotification::Error(ControlError {
+            error: error.to_string(),
+        })
+    }
+}
+
+pub async fn send(channel: &RTCDataChannel, notification: &ControlNotification) {
+    let message = match serde_json::to_string(notification) {
+        Ok(m) => m,
+        Err(e) => {
+            warn!(target: "control", "fail to serialize notification: {}", e);
+            return;
+        }
+    };
+
+    trace!(target: "control", "send notification {}", message);
+    if let Err(e) = channel.send_text(message).await {
+        warn!(target: "control", "fail to send notification: {}", e);
+    }
+}
+
+/// Notifications are sent in background by a single task, so they're delivered in order.
+pub struct ControlQueue {
+    tx: mpsc::UnboundedSender<ControlNotification>,
+    task: JoinHandle<()>,
+}
+
+impl ControlQueue {
+    pub fn new(channel: Arc<RTCDataChannel>) -> Self {
+        let (tx, mut rx) = mpsc::unbounded::<ControlNotification>();
+        let task = actix_web::rt::spawn(async move {
+            while let Some(notification) = rx.next().await {
+                send(&channel, &notification).await;
+            }
+        });
+        Self { tx, task }
+    }
+
+    pub fn notify(&self, notification: ControlNotification) {
+        if self.tx.unbounded_send(notification).is_err() {
+            warn!(target: "control", "notification is sent after the queue is closed");
+        }
+    }
+
+    /// Waits until the queued notifications are sent.
+    pub async fn close(self) {
+        let ControlQueue { tx, task } = self;
+        drop(tx);
+        if let Err(e) = task.await {
+            warn!(target: "control", "control queue task error: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::SessionEvent;
+    use crate::webrtc::control::{ControlNotification, ControlRequest};
+    use serde_json::json;
+
+    #[test]
+    fn protocol_messages() {
+        let request = |v| serde_json::from_value::<ControlRequest>(v).ok();
+
+        assert!(matches!(
+            request(json!({"type": "start"})),
+            Some(ControlRequest::Resume)
+        ));
+        assert!(matches!(
+            request(json!({"type": "recognize", "speech": "neutral"})),
+            Some(ControlRequest::Recognize { track: 0, .. })
+        ));
+        assert!(matches!(
+            request(json!({"type": "recognize", "speech": "neutral", "track": 1})),
+            Some(ControlRequest::Recognize { track: 1, .. })
+        ));
+        assert!(request(json!({"type": "recognize"})).is_none());
+
+        assert_eq!(
+            serde_json::to_value(ControlNotification::Event(SessionEvent::RecordingPaused))
+                .unwrap(),
+            json!({"type": "recording_paused"})
+        );
+        assert_eq!(
+            serde_json::to_value(ControlNotification::error("unknown command")).unwrap(),
+            json!({"type": "error", "error": "unknown command"})
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use crate::UserId;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha1::Sha1;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use webrtc::ice_transport::ice_credential_type::RTCIceCredentialType;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+
+/// STUN and TURN servers shared by the server and the client peer connections.
+#[derive(Debug, Clone, Default)]
+pub struct IceConfig {
+    pub stun: Vec<String>,
+    pub turn: Vec<String>,
+    pub turn_auth: Option<TurnAuth>,
+}
+
+#[derive(Debug, Clone)]
+pub enum TurnAuth {
+    /// Long-term credentials of all sessions
+    Static {
+        username: String,
+        credential: String,
+    },
+    /// `static-auth-secret` of coturn, credentials are minted per session by the TURN REST API scheme
+    Secret { secret: String, ttl: Duration },
+}
+
+/// Server in the format of the browser `RTCIceServer`.
+#[derive(Debug, Clone, Serialize, Eq, PartialEq)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+impl IceConfig {
+    pub fn servers(&self, user_id: UserId) -> Vec<IceServer> {
+        self.servers_at(user_id, SystemTime::now())
+    }
+
+    fn servers_at(&self, user_id: UserId, now: SystemTime) -> Vec<IceServer> {
+        let mut servers = vec![];
+
+        if !self.stun.is_empty() {
+            servers.push(IceServer {
+                urls: self.stun.clone(),
+                username: None,
+                credential: None,
+            });
+        }
+
+        let credentials = match &self.turn_auth {
+            Some(TurnAuth::Static {
+                username,
+                credential,
+            }) => Some((username.clone(), credential.clone())),
+            Some(TurnAuth::Secret { secret, ttl }) => {
+                Some(turn_rest_credentials(secret, *ttl, user_id, now))
+            }
+            None => None,
+        };
+
+        // TURN isn't usable without credentials
+        if let (false, Some((username, credential))) = (self.turn.is_empty(), credentials) {
+            servers.push(IceServer {
+                urls: self.turn.clone(),
+                username: Some(username),
+                credential: Some(credential),
+            });
+        }
+
+        servers
+    }
+}
+
+/// Username is `{expiration}:{user_id}`, credential is base64 encoded HMAC-SHA1 of the username.
+fn turn_rest_credentials(
+    secret: &str,
+    ttl: Duration,
+    user_id: UserId,
+    now: SystemTime,
+) -> (String, String) {
+    let expiration = (now + ttl)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let username = format!("{}:{}", expiration, user_id.0);
+
+    let mut mac: Hmac<Sha1> =
+        Hmac::new_from_slice(secret.as_bytes()).expect("hmac accepts key of any size");
+    mac.update(username.as_bytes());
+
+    (username, base64::encode(mac.finalize().into_bytes()))
+}
+
+impl From<IceServer> for RTCIceServer {
+    fn from(server: IceServer) -> Self {
+        RTCIceServer {
+            urls: server.urls,
+            username: server.username.unwrap_or_default(),
+            credential: server.credential.unwrap_or_default(),
+            credential_type: RTCIceCredentialType::Password,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::webrtc::ice::{IceConfig, IceServer, TurnAuth};
+    use crate::UserId;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn turn_credentials_are_minted_by_secret() {
+        let config = IceConfig {
+            stun: vec!["stun:stun.example.com:3478".to_string()],
+            turn: vec!["turn:turn.example.com:3478".to_string()],
+            turn_auth: Some(TurnAuth::Secret {
+                secret: "secret".to_string(),
+                ttl: Duration::from_secs(3600),
+            }),
+        };
+
+        let servers = config.servers_at(UserId(42), UNIX_EPOCH + Duration::from_secs(1700000000));
+
+        assert_eq!(
+            servers,
+            vec![
+                IceServer {
+                    urls: vec!["stun:stun.example.com:3478".to_string()],
+                    username: None,
+                    credential: None,
+                },
+                IceServer {
+                    urls: vec!["turn:turn.example.com:3478".to_string()],
+                    username: Some("1700003600:42".to_string()),
+                    credential: Some("BiRzwDjrED4KnqX7kNBEFJ1fDaQ=".to_string()),
+                },
+            ]
+        );
+
+        let without_auth = IceConfig {
+            turn_auth: None,
+            ..config
+        };
+        assert_eq!(
+            without_auth.servers_at(UserId(42), SystemTime::now()).len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use webrtc::rtp::packet::Packet;
+
+/// Longer gaps aren't concealed, timestamps of the next packet keep Ogg granules right.
+const MAX_CONCEALED_PACKETS: i64 = 50;
+/// How many sequence numbers of concealed packets are remembered to tell late packets from duplicates.
+const CONCEALED_HISTORY: i64 = 1024;
+
+/// Frame released by `JitterBuffer` in the order of sequence numbers.
+#[derive(Debug)]
+pub enum Frame {
+    Packet(Packet),
+    /// Packet wasn't received in time, its place should be filled with silence
+    Lost {
+        sequence_number: u16,
+        timestamp: u32,
+        /// Duration of the lost packet in RTP timestamp units
+        duration: u32,
+    },
+}
+
+/// Loss and reordering statistics of the track.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize)]
+pub struct JitterStats {
+    pub received: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    /// Packets arrived after their place was concealed
+    pub late: u64,
+}
+
+/// Reorders RTP packets by sequence number and drops duplicates.
+/// In-order packets are released immediately, a gap is waited for until `capacity` packets are buffered after it.
+pub struct JitterBuffer {
+    capacity: usize,
+    packets: BTreeMap<i64, Packet>,
+    /// Extended sequence number of the next released packet
+    next: Option<i64>,
+    highest: i64,
+    /// Extended sequence number and timestamp of the last released packet
+    last_released: Option<(i64, u32)>,
+    concealed: BTreeSet<i64>,
+    stats: JitterStats,
+}
+
+impl JitterBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            packets: BTreeMap::new(),
+            next: None,
+            highest: 0,
+            last_released: None,
+            concealed: BTreeSet::new(),
+            stats: JitterStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        self.stats
+    }
+
+    pub fn push(&mut self, packet: Packet) -> Vec<Frame> {
+        self.stats.received += 1;
+
+        let sequence_number = self.extend(packet.header.sequence_number);
+        let next = *self.next.get_or_insert(sequence_number);
+
+        if sequence_number < next {
+            if self.concealed.remove(&sequence_number) {
+                self.stats.late += 1;
+            } else {
+                self.stats.duplicated += 1;
+            }
+            return vec![];
+        }
+        if self.packets.contains_key(&sequence_number) {
+            self.stats.duplicated += 1;
+            return vec![];
+        }
+        if sequence_number < self.highest {
+            self.stats.reordered += 1;
+        }
+        self.highest = self.highest.max(sequence_number);
+
+        self.packets.insert(sequence_number, packet);
+        self.release(false)
+    }
+
+    /// Releases all buffered packets, the gaps are concealed.
+    pub fn finish(&mut self) -> Vec<Frame> {
+        self.release(true)
+    }
+
+    /// Sequence number is extended by the wraps counted from the highest one.
+    fn extend(&self, sequence_number: u16) -> i64 {
+        let Some(next) = self.next else {
+            return sequence_number as i64;
+        };
+        let highest = self.highest.max(next);
+        highest + (sequence_number.wrapping_sub(highest as u16) as i16) as i64
+    }
+
+    fn release(&mut self, flush: bool) -> Vec<Frame> {
+        let mut frames = vec![];
+
+        while let Some((&first, _)) = self.packets.first_key_value() {
+            let Some(next) = self.next else {
+                break;
+            };
+
+            if first > next {
+                if !flush && self.packets.len() <= self.capacity {
+                    break;
+                }
+                self.conceal(next, first, &mut frames);
+            }
+
+            if let Some((first, packet)) = self.packets.pop_first() {
+                self.last_released = Some((first, packet.header.timestamp));
+                self.next = Some(first + 1);
+                frames.push(Frame::Packet(packet));
+            }
+        }
+
+        frames
+    }
+
+    /// Packets from `from` to `to` (exclusive) are lost.
+    fn conceal(&mut self, from: i64, to: i64, frames: &mut Vec<Frame>) {
+        self.stats.lost += (to - from) as u64;
+        self.concealed.extend(from..to);
+        while let Some(&oldest) = self.concealed.first() {
+            if oldest >= to - CONCEALED_HISTORY {
+                break;
+            }
+            self.concealed.pop_first();
+        }
+
+        let (Some((last, last_timestamp)), Some(next_packet)) =
+            (self.last_released, self.packets.get(&to))
+        else {
+            return;
+        };
+        if to - from > MAX_CONCEALED_PACKETS {
+            return;
+        }
+
+        // duration of lost packets is estimated by the packets around the gap
+        let duration =
+            next_packet.header.timestamp.wrapping_sub(last_timestamp) / (to - last) as u32;
+        if duration == 0 {
+            return;
+        }
+
+        frames.extend((from..to).map(|sequence_number| Frame::Lost {
+            sequence_number: sequence_number as u16,
+            timestamp:
+                last_timestamp.wrapping_add(duration.wrapping_mul((sequence_number - last) as u32)),
+            duration,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::webrtc::jitter::{Frame, JitterBuffer, JitterStats};
+    use webrtc::rtp::header::Header;
+    use webrtc::rtp::packet::Packet;
+
+    const FRAME: u32 = 960;
+    /// Sequence numbers wrap during the tests
+    const FIRST: u16 = 65530;
+
+    fn timestamp(sequence_number: u16) -> u32 {
+        FRAME * sequence_number.wrapping_sub(FIRST) as u32
+    }
+
+    fn packet(sequence_number: u16) -> Packet {
+        Packet {
+            header: Header {
+                sequence_number,
+                timestamp: timestamp(sequence_number),
+                ..Default::default()
+            },
+            payload: vec![0xAB; 80].into(),
+        }
+    }
+
+    fn sequence(frames: Vec<Frame>) -> Vec<(u16, bool)> {
+        frames
+            .into_iter()
+            .map(|f| match f {
+                Frame::Packet(p) => (p.header.sequence_number, true),
+                Frame::Lost {
+                    sequence_number, ..
+                } => (sequence_number, false),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn packets_are_reordered_and_deduplicated() {
+        let mut buffer = JitterBuffer::new(3);
+
+        let released = [1, 3, 2, 2, 4, 1, 5]
+            .into_iter()
+            .flat_map(|n| buffer.push(packet(n)))
+            .collect();
+
+        assert_eq!(
+            sequence(released),
+            vec![(1, true), (2, true), (3, true), (4, true), (5, true)]
+        );
+        assert_eq!(
+            buffer.stats(),
+            JitterStats {
+                received: 7,
+                lost: 0,
+                duplicated: 2,
+                reordered: 1,
+                late: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn gap_is_concealed() {
+        let mut buffer = JitterBuffer::new(2);
+
+        let mut frames = [65534, 65535, 2, 3, 4, 1]
+            .into_iter()
+            .flat_map(|n| buffer.push(packet(n)))
+            .collect::<Vec<_>>();
+        frames.extend(buffer.finish());
+
+        let timestamps = frames
+            .iter()
+            .map(|f| match f {
+                Frame::Packet(p) => p.header.timestamp,
+                Frame::Lost { timestamp, .. } => *timestamp,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            sequence(frames),
+            vec![
+                (65534, true),
+                (65535, true),
+                (0, false),
+                (1, false),
+                (2, true),
+                (3, true),
+                (4, true)
+            ]
+        );
+        assert_eq!(timestamps, [65534, 65535, 0, 1, 2, 3, 4].map(timestamp));
+        assert_eq!(buffer.stats().lost, 2);
+        assert_eq!(buffer.stats().late, 1);
+    }
+}
@@ -1,29 +1,44 @@
 use actix::Addr;
 use dashmap::DashMap;
-use log::info;
-use std::collections::HashSet;
-use std::fs::File;
-use std::path::PathBuf;
+use log::{info, warn};
 use std::sync::Arc;
 use uuid::Uuid;
 use webrtc::api::interceptor_registry::register_default_interceptors;
-use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_OPUS};
+use webrtc::api::media_engine::{
+    MediaEngine, MIME_TYPE_G722, MIME_TYPE_OPUS, MIME_TYPE_PCMA, MIME_TYPE_PCMU,
+};
 use webrtc::api::setting_engine::SettingEngine;
 use webrtc::api::{APIBuilder, API};
+use webrtc::ice::udp_mux::{UDPMuxDefault, UDPMuxParams};
 use webrtc::ice::udp_network::{EphemeralUDP, UDPNetwork};
-use webrtc::ice_transport::ice_credential_type::RTCIceCredentialType;
 use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::interceptor::registry::Registry;
-use webrtc::media::io::ogg_writer::OggWriter;
 use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::rtp_transceiver::rtp_codec::{
-    RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType,
+    RTCRtpCodecCapability, RTCRtpCodecParameters, RTCRtpHeaderExtensionCapability, RTPCodecType,
 };
-
+use webrtc::sdp::extmap::AUDIO_LEVEL_URI;
+
+pub mod control;
+pub mod ice;
+pub mod jitter;
+pub mod network;
+pub mod quota;
+pub mod segmenter;
 mod session;
+pub mod writer;
+use crate::events::broker::EventBroker;
 use crate::garbage::collector::GarbageCollector;
 use crate::{SessionConfig, UserId};
-pub use session::{CloseSession, OfferRequest, OfferResponse, Session};
+use ice::IceServer;
+use network::{NatCandidate, NetworkConfig};
+pub use session::{
+    AddRemoteCandidate, CandidateResponse, CloseReason, CloseSession, Closed, GetLocalCandidates,
+    HasTrack, LiveRecognition, LocalCandidates, OfferRequest, OfferResponse, PauseRecording,
+    ResumeRecording, Session, SessionOptions,
+};
+pub use writer::{find_audio_path, get_audio_path};
+use writer::{AudioWriter, TrackFormat, OPUS_RTPMAP_CHANNELS, OPUS_SAMPLE_RATE};
 
 pub type SessionStorage = DashMap<Uuid, Addr<Session>>;
 
@@ -31,23 +46,40 @@ pub struct PortRange(pub u16, pub u16);
 
 pub fn create_api(
     PortRange(min, max): PortRange,
-    allowed_nets: Option<HashSet<String>>,
+    network: NetworkConfig,
 ) -> webrtc::error::Result<API> {
     let mut m = MediaEngine::default();
 
-    m.register_codec(
-        RTCRtpCodecParameters {
-            capability: RTCRtpCodecCapability {
-                mime_type: MIME_TYPE_OPUS.to_owned(),
-                clock_rate: 48000,
-                channels: 2,
-                sdp_fmtp_line: "".to_owned(),
-                rtcp_feedback: vec![],
+    // Opus is preferred, G.711 and G.722 are offered by SIP gateways and embedded clients
+    for (mime_type, clock_rate, channels, payload_type) in [
+        (MIME_TYPE_OPUS, OPUS_SAMPLE_RATE, OPUS_RTPMAP_CHANNELS, 111),
+        (MIME_TYPE_G722, 8000, 0, 9),
+        (MIME_TYPE_PCMU, 8000, 0, 0),
+        (MIME_TYPE_PCMA, 8000, 0, 8),
+    ] {
+        m.register_codec(
+            RTCRtpCodecParameters {
+                capability: RTCRtpCodecCapability {
+                    mime_type: mime_type.to_owned(),
+                    clock_rate,
+                    channels,
+                    sdp_fmtp_line: "".to_owned(),
+                    rtcp_feedback: vec![],
+                },
+                payload_type,
+                ..Default::default()
             },
-            payload_type: 111,
-            ..Default::default()
+            RTPCodecType::Audio,
+        )?;
+    }
+
+    // levels of packets are used by live recognition to detect silence
+    m.register_header_extension(
+        RTCRtpHeaderExtensionCapability {
+            uri: AUDIO_LEVEL_URI.to_owned(),
         },
         RTPCodecType::Audio,
+        vec![],
     )?;
 
     let mut registry = Registry::new();
@@ -55,14 +87,51 @@ pub fn create_api(
     registry = register_default_interceptors(registry, &mut m)?;
 
     let mut settings = SettingEngine::default();
-    settings.set_udp_network(UDPNetwork::Ephemeral(
-        EphemeralUDP::new(min, max).expect("ports range is invalid"),
-    ));
+    match network.udp_mux {
+        Some(addr) => {
+            let socket = std::net::UdpSocket::bind(addr)
+                .and_then(|s| s.set_nonblocking(true).map(|_| s))
+                .and_then(actix_web::rt::net::UdpSocket::from_std)
+                .map_err(webrtc::util::Error::from)?;
+
+            // TCP candidates aren't gathered by webrtc-ice 0.8, clients without UDP need TURN over TCP
+            info!(target: "webrtc", "peer connections are muxed by udp socket {}", addr);
+            // server reflexive candidates aren't gathered by the muxed socket
+            if network.nat_1to1_candidate == NatCandidate::Srflx && !network.nat_1to1_ips.is_empty()
+            {
+                warn!(target: "webrtc", "srflx nat 1to1 candidates aren't supported with udp mux, use host");
+            }
+
+            settings.set_udp_network(UDPNetwork::Muxed(UDPMuxDefault::new(UDPMuxParams::new(
+                socket,
+            ))));
+        }
+        None => settings.set_udp_network(UDPNetwork::Ephemeral(
+            EphemeralUDP::new(min, max).expect("ports range is invalid"),
+        )),
+    }
 
-    if let Some(nets) = allowed_nets {
+    if let Some(nets) = network
+        .allowed_interfaces()
+        .map_err(webrtc::util::Error::from)?
+    {
+        info!(target: "webrtc", "candidates are gathered from interfaces: {:?}", nets);
         settings.set_interface_filter(Box::new(move |interface| nets.contains(interface)))
     }
 
+    settings.set_network_types(network.family.network_types());
+
+    if !network.nat_1to1_ips.is_empty() {
+        settings.set_nat_1to1_ips(
+            network
+                .nat_1to1_ips
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            network.nat_1to1_candidate.into(),
+        );
+    }
+
     Ok(APIBuilder::new()
         .with_media_engine(m)
         .with_interceptor_registry(registry)
@@ -75,34 +144,37 @@ pub async fn create_session(
     api: &API,
     session_storage: Arc<SessionStorage>,
     garbage_collector: Arc<Addr<GarbageCollector>>,
+    events: Arc<Addr<EventBroker>>,
     config: SessionConfig,
+    options: SessionOptions,
 ) -> std::io::Result<(Uuid, Addr<Session>)> {
     let uuid = Uuid::new_v4();
 
-    let dir = get_audio_path(uuid, config.dir);
+    let dir = config.dir.clone();
+    let quota = config.quota.clone();
 
+    // mono Opus is expected, writer is replaced when the track of another format is received
     let writer = actix_web::web::block(move || {
-        let file = File::create(dir)?;
-
-        OggWriter::new(file, 48000, 2)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+        quota.store_owner(user_id, uuid)?;
+        AudioWriter::create(TrackFormat::DEFAULT, uuid, 0, dir)
     })
     .await
-    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))??;
+    .map_err(std::io::Error::other)??;
 
     let peer = api
-        .new_peer_connection(create_config())
+        .new_peer_connection(create_config(config.ice.servers(user_id)))
         .await
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        .map_err(std::io::Error::other)?;
 
     let session = Session::new(
         uuid,
         user_id,
         garbage_collector,
+        events,
         writer,
         Arc::new(peer),
-        config.total_timeout,
-        config.timeout,
+        config,
+        options,
     );
 
     session_storage.insert(uuid, session.clone());
@@ -112,41 +184,9 @@ pub async fn create_session(
     Ok((uuid, session))
 }
 
-fn create_config() -> RTCConfiguration {
+fn create_config(ice_servers: Vec<IceServer>) -> RTCConfiguration {
     RTCConfiguration {
-        ice_servers: vec![
-            RTCIceServer {
-                urls: vec!["stun:stun.l.google.com:19302".to_owned()],
-                ..Default::default()
-            },
-            RTCIceServer {
-                urls: vec!["stun:openrelay.metered.ca:80".to_owned()],
-                ..Default::default()
-            },
-            RTCIceServer {
-                urls: vec!["turn:openrelay.metered.ca:80".to_owned()],
-                username: "openrelayproject".to_string(),
-                credential: "openrelayproject".to_string(),
-                credential_type: RTCIceCredentialType::Password,
-            },
-            RTCIceServer {
-                urls: vec!["turn:openrelay.metered.ca:443".to_owned()],
-                username: "openrelayproject".to_string(),
-                credential: "openrelayproject".to_string(),
-                credential_type: RTCIceCredentialType::Password,
-            },
-            RTCIceServer {
-                urls: vec!["turn:openrelay.metered.ca:443?transport=tcp".to_owned()],
-                username: "openrelayproject".to_string(),
-                credential: "openrelayproject".to_string(),
-                credential_type: RTCIceCredentialType::Password,
-            },
-        ],
+        ice_servers: ice_servers.into_iter().map(RTCIceServer::from).collect(),
         ..Default::default()
     }
 }
-
-pub fn get_audio_path(uuid: Uuid, mut dir: PathBuf) -> PathBuf {
-    dir.push(format!("{}.ogg", uuid));
-    dir
-}
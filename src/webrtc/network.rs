@@ -0,0 +1,169 @@
+use ipnet::IpNet;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use webrtc::ice::network_type::NetworkType;
+use webrtc::ice_transport::ice_candidate_type::RTCIceCandidateType;
+use webrtc::util::ifaces::{ifaces, Kind};
+
+/// Which local addresses are gathered as candidates and how they're advertised.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkConfig {
+    /// Names of the allowed interfaces, all by default
+    pub interfaces: Option<HashSet<String>>,
+    pub family: IpFamily,
+    /// Interface is allowed when any of its addresses is in these networks, all by default
+    pub networks: Vec<IpNet>,
+    /// Public addresses of 1:1 NAT, e.g. of the node running the container
+    pub nat_1to1_ips: Vec<IpAddr>,
+    pub nat_1to1_candidate: NatCandidate,
+    /// All peer connections share the socket bound to this address instead of the ports range,
+    /// the single host candidate is advertised
+    pub udp_mux: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum IpFamily {
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+/// How the public address of NAT is advertised.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum NatCandidate {
+    /// Replaces the private address of host candidates
+    #[default]
+    Host,
+    /// Added as a server reflexive candidate, host candidates are kept
+    Srflx,
+}
+
+impl FromStr for IpFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(IpFamily::Any),
+            "ipv4" => Ok(IpFamily::Ipv4),
+            "ipv6" => Ok(IpFamily::Ipv6),
+            f => Err(format!("unknown ip family: {}", f)),
+        }
+    }
+}
+
+impl FromStr for NatCandidate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "host" => Ok(NatCandidate::Host),
+            "srflx" => Ok(NatCandidate::Srflx),
+            c => Err(format!("unknown nat candidate type: {}", c)),
+        }
+    }
+}
+
+impl IpFamily {
+    pub fn network_types(self) -> Vec<NetworkType> {
+        match self {
+            IpFamily::Any => vec![NetworkType::Udp4, NetworkType::Udp6],
+            IpFamily::Ipv4 => vec![NetworkType::Udp4],
+            IpFamily::Ipv6 => vec![NetworkType::Udp6],
+        }
+    }
+
+    fn contains(self, ip: &IpAddr) -> bool {
+        match self {
+            IpFamily::Any => true,
+            IpFamily::Ipv4 => ip.is_ipv4(),
+            IpFamily::Ipv6 => ip.is_ipv6(),
+        }
+    }
+}
+
+impl From<NatCandidate> for RTCIceCandidateType {
+    fn from(candidate: NatCandidate) -> Self {
+        match candidate {
+            NatCandidate::Host => RTCIceCandidateType::Host,
+            NatCandidate::Srflx => RTCIceCandidateType::Srflx,
+        }
+    }
+}
+
+impl NetworkConfig {
+    /// Candidates are gathered from interfaces only,
+    /// so the networks select the interfaces having the matching addresses.
+    pub fn allowed_interfaces(&self) -> std::io::Result<Option<HashSet<String>>> {
+        if self.networks.is_empty() {
+            return Ok(self.interfaces.clone());
+        }
+
+        let addresses = ifaces()?
+            .into_iter()
+            .filter(|i| matches!(i.kind, Kind::Ipv4 | Kind::Ipv6))
+            .filter_map(|i| Some((i.name, i.addr?.ip())))
+            .collect::<Vec<_>>();
+
+        Ok(Some(self.filter_interfaces(&addresses)))
+    }
+
+    fn filter_interfaces(&self, addresses: &[(String, IpAddr)]) -> HashSet<String> {
+        addresses
+            .iter()
+            .filter(|(name, _)| self.interfaces.as_ref().is_none_or(|i| i.contains(name)))
+            .filter(|(_, ip)| {
+                self.family.contains(ip) && self.networks.iter().any(|n| n.contains(ip))
+            })
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::webrtc::network::{IpFamily, NetworkConfig};
+    use std::collections::HashSet;
+
+    #[test]
+    fn interfaces_are_filtered_by_networks() {
+        let addresses = [
+            ("lo", "127.0.0.1"),
+            ("eth0", "10.1.2.3"),
+            ("eth0", "fd00::3"),
+            ("eth1", "192.168.0.5"),
+            ("docker0", "172.17.0.1"),
+        ]
+        .map(|(name, ip)| (name.to_string(), ip.parse().unwrap()));
+
+        let config = NetworkConfig {
+            networks: vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(
+            config.filter_interfaces(&addresses),
+            HashSet::from(["eth0".to_string()])
+        );
+
+        let config = NetworkConfig {
+            family: IpFamily::Ipv6,
+            networks: vec!["::/0".parse().unwrap(), "0.0.0.0/0".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(
+            config.filter_interfaces(&addresses),
+            HashSet::from(["eth0".to_string()])
+        );
+
+        let config = NetworkConfig {
+            interfaces: Some(HashSet::from(["eth1".to_string()])),
+            networks: vec!["0.0.0.0/0".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(
+            config.filter_interfaces(&addresses),
+            HashSet::from(["eth1".to_string()])
+        );
+    }
+}
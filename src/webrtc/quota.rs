@@ -0,0 +1,437 @@
+use crate::webrtc::writer::get_track_paths;
+use crate::UserId;
+use dashmap::DashMap;
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Owner of the session recordings, it's read when the recordings are counted again after a restart.
+/// Only sessions with the owner are counted and removed, other files of `AUDIO_DIR` aren't touched.
+const OWNER_EXTENSION: &str = "owner";
+
+/// Limits of the recorded bytes, `None` is unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QuotaLimits {
+    pub session: Option<u64>,
+    pub user: Option<u64>,
+    /// Recordings of all users stored in `AUDIO_DIR`
+    pub total: Option<u64>,
+    /// Free space of `AUDIO_DIR` reported by the file system, the counted bytes don't include other files
+    pub min_free: Option<u64>,
+}
+
+/// Which limit was crossed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum QuotaExceeded {
+    Session,
+    User,
+    Total,
+}
+
+/// Counts bytes of the recordings until they're removed by the garbage collector.
+#[derive(Debug)]
+pub struct StorageQuota {
+    limits: QuotaLimits,
+    /// Directory of the recordings, its free space is checked
+    dir: PathBuf,
+    /// Free space of `dir` measured by `watch_free_space`, it's unknown until the first measure
+    free: AtomicU64,
+    total: AtomicU64,
+    users: DashMap<UserId, u64>,
+    /// Recordings left by the previous run don't have an owner if it can't be read
+    sessions: DashMap<Uuid, (Option<UserId>, u64)>,
+}
+
+impl StorageQuota {
+    pub fn new(limits: QuotaLimits, dir: PathBuf) -> Self {
+        Self {
+            limits,
+            dir,
+            free: AtomicU64::new(u64::MAX),
+            total: Default::default(),
+            users: Default::default(),
+            sessions: Default::default(),
+        }
+    }
+
+    /// Counts recordings stored in the directory by the previous run, returns their sessions.
+    pub fn seed(&self) -> Vec<Uuid> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!(target: "quota", "fail to read audio dir {:?}: {}", self.dir, e);
+                return vec![];
+            }
+        };
+
+        let sessions = entries
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_str()?
+                    .strip_suffix(&format!(".{}", OWNER_EXTENSION))?
+                    .parse::<Uuid>()
+                    .ok()
+            })
+            .collect::<Vec<_>>();
+
+        for &session_id in &sessions {
+            let bytes = session_files(&self.dir, session_id)
+                .iter()
+                .filter_map(|path| match std::fs::metadata(path) {
+                    Ok(m) => Some(m.len()),
+                    Err(e) => {
+                        warn!(target: "quota", "fail to get size of {:?}: {}", path, e);
+                        None
+                    }
+                })
+                .sum::<u64>();
+            let user_id = read_owner(&self.dir, session_id);
+            self.sessions.insert(session_id, (user_id, bytes));
+            if let Some(user_id) = user_id {
+                *self.users.entry(user_id).or_default() += bytes;
+            }
+            self.total.fetch_add(bytes, Ordering::Relaxed);
+        }
+        info!(
+            target: "quota",
+            "{} bytes of {} sessions are already stored",
+            self.total.load(Ordering::Relaxed),
+            sessions.len()
+        );
+
+        sessions
+    }
+
+    /// Stores the owner of the session next to its recordings.
+    pub fn store_owner(&self, user_id: UserId, session_id: Uuid) -> std::io::Result<()> {
+        std::fs::write(owner_path(&self.dir, session_id), user_id.0.to_string())
+    }
+
+    /// New session can't be created when the user or total storage is already full.
+    pub fn check(&self, user_id: UserId) -> Result<(), QuotaExceeded> {
+        let user = self.users.get(&user_id).map(|u| *u).unwrap_or_default();
+        if self.limits.user.is_some_and(|l| user >= l) {
+            return Err(QuotaExceeded::User);
+        }
+        if self
+            .limits
+            .total
+            .is_some_and(|l| self.total.load(Ordering::Relaxed) >= l)
+        {
+            return Err(QuotaExceeded::Total);
+        }
+        self.check_free_space()
+    }
+
+    /// Writing sessions are closed when the disk is running out, the last measured free space is checked.
+    pub fn check_free_space(&self) -> Result<(), QuotaExceeded> {
+        match self.limits.min_free {
+            Some(min_free) if self.free.load(Ordering::Relaxed) < min_free => {
+                Err(QuotaExceeded::Total)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Measures the free space of the directory periodically, nothing is measured without `QuotaLimits::min_free`.
+    pub async fn watch_free_space(self: Arc<Self>, interval: Duration) {
+        if self.limits.min_free.is_none() {
+            return;
+        }
+        let mut interval = actix_web::rt::time::interval(interval);
+        loop {
+            interval.tick().await;
+            self.measure_free_space().await;
+        }
+    }
+
+    /// File system is queried on the blocking pool.
+    async fn measure_free_space(&self) {
+        let dir = self.dir.clone();
+        let free = actix_web::web::block(move || fs2::available_space(dir))
+            .await
+            .map_err(std::io::Error::other)
+            .and_then(std::convert::identity);
+        match free {
+            Ok(free) => self.free.store(free, Ordering::Relaxed),
+            Err(e) => warn!(target: "quota", "fail to get free space of {:?}: {}", self.dir, e),
+        }
+    }
+
+    /// Accounts bytes written by the session, fails when any limit is crossed.
+    pub fn record(
+        &self,
+        user_id: UserId,
+        session_id: Uuid,
+        bytes: u64,
+    ) -> Result<(), QuotaExceeded> {
+        let session = {
+            let mut session = self
+                .sessions
+                .entry(session_id)
+                .or_insert((Some(user_id), 0));
+            session.1 += bytes;
+            session.1
+        };
+        let user = {
+            let mut user = self.users.entry(user_id).or_default();
+            *user += bytes;
+            *user
+        };
+        let total = self.total.fetch_add(bytes, Ordering::Relaxed) + bytes;
+
+        if self.limits.session.is_some_and(|l| session > l) {
+            Err(QuotaExceeded::Session)
+        } else if self.limits.user.is_some_and(|l| user > l) {
+            Err(QuotaExceeded::User)
+        } else if self.limits.total.is_some_and(|l| total > l) {
+            Err(QuotaExceeded::Total)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Replaces counted bytes of a recording by its size on the disk, e.g. after it's converted.
+    /// Fails when the grown recording crosses any limit, its bytes are counted anyway.
+    pub fn resize(
+        &self,
+        user_id: UserId,
+        session_id: Uuid,
+        from: u64,
+        to: u64,
+    ) -> Result<(), QuotaExceeded> {
+        if to >= from {
+            return self.record(user_id, session_id, to - from);
+        }
+
+        self.free(user_id, session_id, from - to);
+        Ok(())
+    }
+
+    /// Frees bytes of a removed file of the session, e.g. a live segment.
+    /// Nothing is freed after the session is released.
+    pub fn free(&self, user_id: UserId, session_id: Uuid, bytes: u64) {
+        let bytes = match self.sessions.get_mut(&session_id) {
+            Some(mut session) => {
+                let bytes = bytes.min(session.1);
+                session.1 -= bytes;
+                bytes
+            }
+            None => return,
+        };
+        self.free_bytes(Some(user_id), bytes);
+    }
+
+    /// Recordings of the session are removed, its bytes are freed.
+    pub fn release(&self, session_id: Uuid) {
+        let Some((_, (user_id, bytes))) = self.sessions.remove(&session_id) else {
+            return;
+        };
+        self.free_bytes(user_id, bytes);
+    }
+
+    fn free_bytes(&self, user_id: Option<UserId>, bytes: u64) {
+        let _ = self
+            .total
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |t| {
+                Some(t.saturating_sub(bytes))
+            });
+        let Some(user_id) = user_id else {
+            return;
+        };
+        if let Some(mut user) = self.users.get_mut(&user_id) {
+            *user = user.saturating_sub(bytes);
+        }
+        self.users.remove_if(&user_id, |_, bytes| *bytes == 0);
+    }
+}
+
+/// Stored files of the session: its owner, recordings of the tracks and their converted files.
+/// Tracks are numbered in order, so they're looked up until the first missing one.
+pub fn session_files(dir: &Path, session_id: Uuid) -> Vec<PathBuf> {
+    let mut files = vec![];
+    for track in 0.. {
+        let paths = get_track_paths(session_id, track, dir.to_path_buf())
+            .into_iter()
+            .filter(|p| p.exists())
+            .collect::<Vec<_>>();
+        if paths.is_empty() {
+            break;
+        }
+        files.extend(paths);
+    }
+    let owner = owner_path(dir, session_id);
+    if owner.exists() {
+        files.push(owner);
+    }
+    files
+}
+
+fn owner_path(dir: &Path, session_id: Uuid) -> PathBuf {
+    dir.join(format!("{}.{}", session_id, OWNER_EXTENSION))
+}
+
+fn read_owner(dir: &Path, session_id: Uuid) -> Option<UserId> {
+    std::fs::read_to_string(owner_path(dir, session_id))
+        .ok()
+        .and_then(|owner| owner.trim().parse().ok())
+        .map(UserId)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::webrtc::quota::{session_files, QuotaExceeded, QuotaLimits, StorageQuota};
+    use crate::UserId;
+    use uuid::Uuid;
+
+    #[test]
+    fn limits_are_enforced_until_release() {
+        let quota = StorageQuota::new(
+            QuotaLimits {
+                session: Some(100),
+                user: Some(150),
+                total: Some(250),
+                min_free: None,
+            },
+            std::env::temp_dir(),
+        );
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert_eq!(quota.record(UserId(1), first, 100), Ok(()));
+        assert_eq!(
+            quota.record(UserId(1), first, 1),
+            Err(QuotaExceeded::Session)
+        );
+        assert_eq!(
+            quota.record(UserId(1), second, 50),
+            Err(QuotaExceeded::User)
+        );
+        assert_eq!(quota.check(UserId(1)), Err(QuotaExceeded::User));
+        assert_eq!(
+            quota.record(UserId(2), Uuid::new_v4(), 100),
+            Err(QuotaExceeded::Total)
+        );
+        assert_eq!(quota.check(UserId(3)), Err(QuotaExceeded::Total));
+
+        quota.release(first);
+        quota.release(second);
+        assert_eq!(quota.check(UserId(1)), Ok(()));
+        assert_eq!(quota.check(UserId(3)), Ok(()));
+    }
+
+    #[test]
+    fn converted_recordings_are_resized() {
+        let quota = StorageQuota::new(
+            QuotaLimits {
+                session: Some(100),
+                ..Default::default()
+            },
+            std::env::temp_dir(),
+        );
+        let session = Uuid::new_v4();
+
+        assert_eq!(quota.record(UserId(1), session, 40), Ok(()));
+        assert_eq!(
+            quota.resize(UserId(1), session, 40, 400),
+            Err(QuotaExceeded::Session)
+        );
+        assert_eq!(quota.resize(UserId(1), session, 400, 30), Ok(()));
+        assert_eq!(quota.record(UserId(1), session, 70), Ok(()));
+        assert_eq!(
+            quota.record(UserId(1), session, 1),
+            Err(QuotaExceeded::Session)
+        );
+    }
+
+    #[test]
+    fn removed_files_are_freed_until_release() {
+        let quota = StorageQuota::new(
+            QuotaLimits {
+                user: Some(100),
+                ..Default::default()
+            },
+            std::env::temp_dir(),
+        );
+        let (segments, other) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert_eq!(quota.record(UserId(1), segments, 40), Ok(()));
+        quota.free(UserId(1), segments, 30);
+        assert_eq!(quota.record(UserId(1), other, 60), Ok(()));
+
+        // released session doesn't free bytes of the others
+        quota.release(segments);
+        quota.free(UserId(1), segments, 50);
+        assert_eq!(quota.record(UserId(1), other, 40), Ok(()));
+        assert_eq!(quota.record(UserId(1), other, 1), Err(QuotaExceeded::User));
+    }
+
+    #[test]
+    fn stored_recordings_are_counted() {
+        let dir = std::env::temp_dir().join(format!("wacr-quota-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let (owned, foreign) = (Uuid::new_v4(), Uuid::new_v4());
+        std::fs::write(dir.join(format!("{}.ogg", owned)), [0; 100]).unwrap();
+        std::fs::write(dir.join(format!("{}.track1.wav", owned)), [0; 50]).unwrap();
+        // files without the owner weren't written by the server
+        std::fs::write(dir.join(format!("{}.wav", foreign)), [0; 100]).unwrap();
+        std::fs::write(dir.join("other.txt"), [0; 1000]).unwrap();
+
+        let quota = StorageQuota::new(
+            QuotaLimits {
+                user: Some(160),
+                total: Some(200),
+                ..Default::default()
+            },
+            dir.clone(),
+        );
+        quota.store_owner(UserId(1), owned).unwrap();
+        assert_eq!(quota.seed(), [owned]);
+        assert_eq!(session_files(&dir, owned).len(), 3);
+
+        // owner file is counted with the recordings
+        assert_eq!(quota.check(UserId(1)), Ok(()));
+        assert_eq!(
+            quota.record(UserId(1), Uuid::new_v4(), 10),
+            Err(QuotaExceeded::User)
+        );
+        assert_eq!(
+            quota.record(UserId(2), Uuid::new_v4(), 40),
+            Err(QuotaExceeded::Total)
+        );
+
+        quota.release(owned);
+        assert_eq!(quota.check(UserId(1)), Ok(()));
+        assert_eq!(quota.check(UserId(2)), Ok(()));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn free_space_is_checked() {
+        let limits = QuotaLimits {
+            min_free: Some(1),
+            ..Default::default()
+        };
+        let quota = StorageQuota::new(limits, std::env::temp_dir());
+        quota.measure_free_space().await;
+        assert_eq!(quota.check(UserId(1)), Ok(()));
+
+        let quota = StorageQuota::new(
+            QuotaLimits {
+                min_free: Some(u64::MAX),
+                ..limits
+            },
+            std::env::temp_dir(),
+        );
+        // free space isn't known before it's measured
+        assert_eq!(quota.check(UserId(1)), Ok(()));
+        quota.measure_free_space().await;
+        assert_eq!(quota.check(UserId(1)), Err(QuotaExceeded::Total));
+        assert_eq!(quota.check_free_space(), Err(QuotaExceeded::Total));
+    }
+}
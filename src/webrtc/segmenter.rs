@@ -0,0 +1,328 @@
+use crate::webrtc::writer::{file_stem, ogg_packet_size, OPUS_SAMPLE_RATE};
+use log::warn;
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::Duration;
+use uuid::Uuid;
+use webrtc::media::io::ogg_writer::OggWriter;
+use webrtc::media::io::Writer;
+use webrtc::rtp::extension::audio_level_extension::AudioLevelExtension;
+use webrtc::rtp::packet::Packet;
+use webrtc::util::Unmarshal;
+
+/// Opus packets of silence (DTX and comfort noise) are only a few bytes long.
+const SILENT_PAYLOAD_SIZE: usize = 10;
+/// Segments are recognized, so they're decoded as mono.
+const SEGMENT_CHANNELS: u8 = 1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentConfig {
+    /// Segment is cut when it's longer
+    pub max_duration: Duration,
+    /// Segment is cut after the speech is followed by the silence of this duration
+    pub silence: Duration,
+    /// Packets with the audio level below this dBov are silence
+    pub threshold: f32,
+}
+
+/// Cuts the incoming RTP stream into short Ogg files, so they can be recognized
+/// while the session is still writing.
+/// Segment starts on the first voiced packet, durations are measured by RTP timestamps.
+/// Silence is detected by the audio level header extension (RFC 6464) sent by browsers,
+/// without it only the short packets of Opus DTX are silence.
+pub struct Segmenter {
+    id: Uuid,
+    track: usize,
+    dir: PathBuf,
+    config: SegmentConfig,
+    /// Id of the negotiated audio level header extension
+    audio_level: Option<u8>,
+    next_index: usize,
+    current: Option<Segment>,
+}
+
+struct Segment {
+    index: usize,
+    path: PathBuf,
+    writer: OggWriter<File>,
+    /// Bytes of the written packets, they're counted by the quota
+    bytes: u64,
+    started_at: u32,
+    silence_started_at: Option<u32>,
+}
+
+/// Closed segment ready for recognition.
+#[derive(Debug)]
+pub struct FinishedSegment {
+    pub track: usize,
+    pub index: usize,
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+impl Segmenter {
+    pub fn new(id: Uuid, track: usize, dir: PathBuf, config: SegmentConfig) -> Self {
+        Self {
+            id,
+            track,
+            dir,
+            config,
+            audio_level: None,
+            next_index: 0,
+            current: None,
+        }
+    }
+
+    /// Extension id is known after the remote track is received.
+    pub fn set_audio_level(&mut self, id: Option<u8>) {
+        self.audio_level = id;
+    }
+
+    fn is_silent(&self, packet: &Packet) -> bool {
+        let level = self
+            .audio_level
+            .and_then(|id| packet.header.get_extension(id))
+            .and_then(|mut e| AudioLevelExtension::unmarshal(&mut e).ok());
+
+        packet.payload.len() <= SILENT_PAYLOAD_SIZE
+            || level.is_some_and(|l| -(l.level as f32) < self.config.threshold)
+    }
+
+    /// Writes the packet to the current segment,
+    /// returns how many bytes the packet took and the segment if it was cut.
+    pub fn write_rtp(&mut self, packet: &Packet) -> (u64, Option<FinishedSegment>) {
+        let SegmentConfig {
+            max_duration,
+            silence,
+            ..
+        } = self.config;
+        let silent = self.is_silent(packet);
+        let timestamp = packet.header.timestamp;
+
+        let segment = match &mut self.current {
+            Some(s) => s,
+            None if silent => return (0, None),
+            None => match self.start_segment(timestamp) {
+                Some(s) => s,
+                None => return (0, None),
+            },
+        };
+
+        let bytes = match segment.writer.write_rtp(packet) {
+            Ok(_) => ogg_packet_size(packet.payload.len() as u64),
+            Err(e) => {
+                warn!(target: "segmenter", "write rtp error: {}", e);
+                0
+            }
+        };
+        segment.bytes += bytes;
+
+        segment.silence_started_at = match (silent, segment.silence_started_at) {
+            (false, _) => None,
+            (true, None) => Some(timestamp),
+            (true, started_at) => started_at,
+        };
+
+        let too_long = elapsed(segment.started_at, timestamp) >= max_duration;
+        let silence_is_over = segment
+            .silence_started_at
+            .is_some_and(|s| elapsed(s, timestamp) >= silence);
+
+        if too_long || silence_is_over {
+            (bytes, self.finish())
+        } else {
+            (bytes, None)
+        }
+    }
+
+    /// Closes the current segment, if it was started.
+    pub fn finish(&mut self) -> Option<FinishedSegment> {
+        let Segment {
+            index,
+            path,
+            mut writer,
+            bytes,
+            ..
+        } = self.current.take()?;
+
+        if let Err(e) = writer.close() {
+            warn!(target: "segmenter", "close segment writer error: {}", e);
+        }
+
+        Some(FinishedSegment {
+            track: self.track,
+            index,
+            path,
+            bytes,
+        })
+    }
+
+    fn start_segment(&mut self, timestamp: u32) -> Option<&mut Segment> {
+        let index = self.next_index;
+        let path = get_segment_path(self.id, self.track, index, self.dir.clone());
+
+        let writer = File::create(&path).and_then(|f| {
+            OggWriter::new(f, OPUS_SAMPLE_RATE, SEGMENT_CHANNELS).map_err(std::io::Error::other)
+        });
+        let writer = match writer {
+            Ok(w) => w,
+            Err(e) => {
+                warn!(target: "segmenter", "fail to create segment {:?}: {}", path, e);
+                return None;
+            }
+        };
+
+        self.next_index += 1;
+        Some(self.current.insert(Segment {
+            index,
+            path,
+            writer,
+            bytes: 0,
+            started_at: timestamp,
+            silence_started_at: None,
+        }))
+    }
+}
+
+fn elapsed(from: u32, to: u32) -> Duration {
+    Duration::from_secs_f64(to.wrapping_sub(from) as f64 / OPUS_SAMPLE_RATE as f64)
+}
+
+pub fn get_segment_path(uuid: Uuid, track: usize, index: usize, mut dir: PathBuf) -> PathBuf {
+    dir.push(format!("{}.part{}.ogg", file_stem(uuid, track), index));
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::webrtc::segmenter::{SegmentConfig, Segmenter};
+    use std::time::Duration;
+    use uuid::Uuid;
+    use webrtc::rtp::extension::audio_level_extension::AudioLevelExtension;
+    use webrtc::rtp::header::Header;
+    use webrtc::rtp::packet::Packet;
+    use webrtc::util::Marshal;
+
+    const AUDIO_LEVEL_ID: u8 = 1;
+
+    /// 20ms of Opus at 48kHz
+    const FRAME: u32 = 960;
+
+    fn packet(sequence_number: u16, voiced: bool) -> Packet {
+        Packet {
+            header: Header {
+                sequence_number,
+                timestamp: 1000 + FRAME * sequence_number as u32,
+                ..Default::default()
+            },
+            payload: vec![0xAB; if voiced { 80 } else { 3 }].into(),
+        }
+    }
+
+    /// Packet without DTX, its level is sent by the audio level extension.
+    fn leveled_packet(sequence_number: u16, level: u8) -> Packet {
+        let mut packet = packet(sequence_number, true);
+        packet
+            .header
+            .set_extension(
+                AUDIO_LEVEL_ID,
+                AudioLevelExtension {
+                    level,
+                    voice: false,
+                }
+                .marshal()
+                .unwrap(),
+            )
+            .unwrap();
+        packet
+    }
+
+    fn segmenter() -> Segmenter {
+        Segmenter::new(
+            Uuid::new_v4(),
+            1,
+            std::env::temp_dir(),
+            SegmentConfig {
+                max_duration: Duration::from_secs(1),
+                silence: Duration::from_millis(200),
+                threshold: -45.0,
+            },
+        )
+    }
+
+    #[test]
+    fn segment_is_cut_by_max_duration() {
+        let mut segmenter = segmenter();
+
+        let cut = (0..100)
+            .filter_map(|n| segmenter.write_rtp(&packet(n, true)).1)
+            .map(|s| {
+                std::fs::remove_file(&s.path).unwrap();
+                s.index
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(cut, [0]);
+        let last = segmenter.finish().expect("last segment wasn't started");
+        std::fs::remove_file(last.path).unwrap();
+    }
+
+    #[test]
+    fn segment_is_cut_on_silence() {
+        let mut segmenter = segmenter();
+
+        // silence before the speech isn't written
+        for n in 0..5 {
+            let (bytes, segment) = segmenter.write_rtp(&packet(n, false));
+            assert_eq!(bytes, 0);
+            assert!(segment.is_none());
+        }
+        let mut written = 0;
+        for n in 5..15 {
+            let (bytes, segment) = segmenter.write_rtp(&packet(n, true));
+            assert!(segment.is_none());
+            written += bytes;
+        }
+
+        let cut = (15..40).find_map(|n| {
+            let (bytes, segment) = segmenter.write_rtp(&packet(n, false));
+            written += bytes;
+            segment.map(|s| (n, s))
+        });
+        let (n, segment) = cut.expect("segment wasn't cut");
+
+        assert_eq!(n, 25);
+        assert_eq!(segment.index, 0);
+        assert_eq!(segment.bytes, written);
+        assert!(segment.path.exists());
+        std::fs::remove_file(segment.path).unwrap();
+        assert!(segmenter.finish().is_none());
+    }
+
+    #[test]
+    fn segment_is_cut_on_low_audio_level() {
+        let mut segmenter = segmenter();
+        segmenter.set_audio_level(Some(AUDIO_LEVEL_ID));
+
+        for n in 0..10 {
+            assert!(segmenter.write_rtp(&leveled_packet(n, 30)).1.is_none());
+        }
+
+        let cut = (10..40).find_map(|n| {
+            segmenter
+                .write_rtp(&leveled_packet(n, 70))
+                .1
+                .map(|s| (n, s))
+        });
+        let (n, segment) = cut.expect("segment wasn't cut");
+
+        assert_eq!(n, 20);
+        assert_eq!(segment.track, 1);
+        assert!(segment
+            .path
+            .to_string_lossy()
+            .ends_with(".track1.part0.ogg"));
+        std::fs::remove_file(segment.path).unwrap();
+        assert!(segmenter.finish().is_none());
+    }
+}
@@ -1,58 +1,257 @@
+use crate::asr::client::SpeechModel;
+use crate::asr::error::AsrError;
+use crate::asr::launcher::{AsrLauncher, AsrRequest};
+use crate::asr::processor::{ProcessResponse, WaitForResponse};
+use crate::asr::recognizer::{AsrProgress, SpeechRecognizer};
+use crate::audio::transcode::Transcoder;
+use crate::audio::AudioFormat;
+use crate::events::broker::{EventBroker, Publish};
+use crate::events::SessionEvent;
 use crate::garbage::collector::{ClearSession, GarbageCollector};
-use crate::UserId;
+use crate::webrtc::control::{
+    control_channel_init, ControlNotification, ControlQueue, ControlRequest, CONTROL_CHANNEL_LABEL,
+};
+use crate::webrtc::get_audio_path;
+use crate::webrtc::jitter::{Frame, JitterBuffer};
+use crate::webrtc::quota::{QuotaExceeded, StorageQuota};
+use crate::webrtc::segmenter::{FinishedSegment, SegmentConfig, Segmenter};
+use crate::webrtc::writer::{get_recorded_path, AudioCodec, AudioWriter, TrackFormat};
+use crate::{SessionConfig, UserId};
 use actix::prelude::*;
 use log::{debug, error, trace, warn};
-use std::fs::File;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use uuid::Uuid;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
 use webrtc::ice_transport::ice_connection_state::RTCIceConnectionState;
-use webrtc::media::io::ogg_writer::OggWriter;
-use webrtc::media::io::Writer;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp::header::Header;
 use webrtc::rtp::packet::Packet;
 use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
 use webrtc::rtp_transceiver::rtp_receiver::RTCRtpReceiver;
+use webrtc::sdp::extmap::AUDIO_LEVEL_URI;
 use webrtc::track::track_remote::TrackRemote;
 
 const CHECKS_INTERVAL: Duration = Duration::from_secs(10);
+/// Live segments are queued while this number of them is being recognized
+const MAX_SEGMENT_RECOGNITIONS: usize = 2;
+/// The oldest queued segment is dropped when the recognizer falls behind by more segments
+const MAX_QUEUED_SEGMENTS: usize = 8;
 
 pub struct Session {
     id: Uuid,
     user_id: UserId,
     garbage_collector: Arc<Addr<GarbageCollector>>,
-    writer: OggWriter<File>,
+    events: Arc<Addr<EventBroker>>,
+    tracks: Vec<TrackRecording>,
+    format: Option<AudioFormat>,
+    mono: bool,
+    dir: PathBuf,
+    transcoder: Transcoder,
+    jitter_buffer: usize,
+    quota: Arc<StorageQuota>,
     peer_connection: Arc<RTCPeerConnection>,
     startup: Instant,
-    update_time: Instant,
+    keep_alive: KeepAlive,
     total_timeout: Duration,
+    ice_grace_period: Duration,
+    /// Session is closed for this reason when ICE isn't restored until the grace period is over
+    ice_lost: Option<(CloseReason, SpawnHandle)>,
+    first_packet_received: bool,
+    /// Packets aren't written while the recording is paused
+    paused: bool,
+    close_reason: Option<CloseReason>,
+    live: Option<LiveRecognition>,
+    segment: SegmentConfig,
+    /// Cut segments waiting for recognition
+    segments: VecDeque<FinishedSegment>,
+    /// Recognitions of segments by their track and index, they're cancelled when the session is closed
+    recognitions: HashMap<(usize, usize), SpawnHandle>,
+    /// Data channel of `ControlRequest`s, session events are sent back over it
+    control: Option<ControlQueue>,
+    asr: Option<AsrLauncher>,
+    /// Recognition of the track requested over the control channel, it's started after the recording is finalized
+    recognize: Option<(SpeechModel, usize)>,
+    /// Gathered candidates of the server, they're fetched by the client when ICE is trickled
+    local_candidates: Vec<RTCIceCandidateInit>,
+    gathering_complete: bool,
+    /// ICE ufrag and pwd of the last offer, the client changes them to restart ICE
+    remote_ice: Option<(String, String)>,
+    /// Recording is being finalized after the session was closed
+    closing: bool,
+    finalized: bool,
+    close_waiters: Vec<futures::channel::oneshot::Sender<()>>,
+}
+
+/// Each remote track is recorded to its own file.
+struct TrackRecording {
+    format: TrackFormat,
+    /// `None` until the remote track is received
+    ssrc: Option<u32>,
+    writer: Option<AudioWriter>,
+    /// Bytes counted by the quota while the track is written
+    written: u64,
+    /// Cuts the track for live recognition, only Opus tracks are segmented
+    segmenter: Option<Segmenter>,
+    jitter: JitterBuffer,
+    rebase: TimestampRebase,
+}
+
+/// Timestamps written after a pause are shifted back by the paused time,
+/// so the recording continues without a gap.
+#[derive(Debug, Default)]
+struct TimestampRebase {
+    offset: u32,
+    /// Original timestamp and duration of the last written packet
+    last: Option<(u32, u32)>,
+    resumed: bool,
+}
+
+impl TimestampRebase {
+    fn rebase(&mut self, timestamp: u32) -> u32 {
+        match self.last {
+            Some((last, duration)) if std::mem::take(&mut self.resumed) => {
+                let paused = timestamp.wrapping_sub(last).wrapping_sub(duration);
+                self.offset = self.offset.wrapping_add(paused);
+                self.last = Some((timestamp, duration));
+            }
+            Some((last, _)) => self.last = Some((timestamp, timestamp.wrapping_sub(last))),
+            None => self.last = Some((timestamp, 0)),
+        }
+        timestamp.wrapping_sub(self.offset)
+    }
+}
+
+/// Session is kept alive by packets and control requests,
+/// the timeout isn't checked while ICE is being restarted.
+#[derive(Debug)]
+struct KeepAlive {
+    update_time: Instant,
     timeout: Duration,
+    suspended: bool,
+}
+
+impl KeepAlive {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            update_time: Instant::now(),
+            timeout,
+            suspended: false,
+        }
+    }
+
+    fn refresh(&mut self) {
+        self.update_time = Instant::now();
+    }
+
+    fn suspend(&mut self) {
+        self.suspended = true;
+    }
+
+    /// Packets weren't received while ICE was lost, so the timeout starts over.
+    fn resume(&mut self) {
+        self.suspended = false;
+        self.refresh();
+    }
+
+    fn is_expired(&self) -> bool {
+        !self.suspended && self.update_time.elapsed() > self.timeout
+    }
+}
+
+/// Options of the session chosen by the client.
+#[derive(Default)]
+pub struct SessionOptions {
+    pub live: Option<LiveRecognition>,
+    /// Recording is converted to this format after the session is closed,
+    /// by default the format depends on the codec
+    pub format: Option<AudioFormat>,
+    /// Stereo recording is downmixed to mono after the session is closed
+    pub mono: bool,
+    /// Recognition can be requested over the control channel when it's set
+    pub asr: Option<AsrLauncher>,
+}
+
+/// Recognition of the speech while the session is writing.
+/// The stream is cut into segments by `Segmenter`, each one is recognized separately.
+pub struct LiveRecognition {
+    pub recognizer: Arc<dyn SpeechRecognizer>,
+    pub speech_model: SpeechModel,
+    pub deadline: Duration,
 }
 
 impl Session {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: Uuid,
         user_id: UserId,
         garbage_collector: Arc<Addr<GarbageCollector>>,
-        writer: OggWriter<File>,
+        events: Arc<Addr<EventBroker>>,
+        writer: AudioWriter,
         peer_connection: Arc<RTCPeerConnection>,
-        total_timeout: Duration,
-        timeout: Duration,
+        config: SessionConfig,
+        SessionOptions {
+            live,
+            format,
+            mono,
+            asr,
+        }: SessionOptions,
     ) -> Addr<Self> {
         Self::create(|ctx| {
             let addr = ctx.address();
 
+            let segmenter = live
+                .is_some()
+                .then(|| Segmenter::new(id, 0, config.dir.clone(), config.segment));
+
             let session = Session {
                 id,
                 user_id,
                 garbage_collector,
-                writer,
+                events: events.clone(),
+                tracks: vec![TrackRecording {
+                    format: TrackFormat::DEFAULT,
+                    ssrc: None,
+                    writer: Some(writer),
+                    written: 0,
+                    segmenter,
+                    jitter: JitterBuffer::new(config.jitter_buffer),
+                    rebase: TimestampRebase::default(),
+                }],
+                format,
+                mono,
+                dir: config.dir.clone(),
+                transcoder: config.transcoder.clone(),
+                jitter_buffer: config.jitter_buffer,
+                quota: config.quota.clone(),
                 peer_connection: peer_connection.clone(),
                 startup: Instant::now(),
-                update_time: Instant::now(),
-                total_timeout,
-                timeout,
+                keep_alive: KeepAlive::new(config.timeout),
+                total_timeout: config.total_timeout,
+                ice_grace_period: config.ice_grace_period,
+                ice_lost: None,
+                first_packet_received: false,
+                paused: false,
+                close_reason: None,
+                live,
+                segment: config.segment,
+                segments: VecDeque::new(),
+                recognitions: HashMap::new(),
+                control: None,
+                asr,
+                recognize: None,
+                local_candidates: vec![],
+                gathering_complete: false,
+                remote_ice: None,
+                closing: false,
+                finalized: false,
+                close_waiters: vec![],
             };
 
             ctx.spawn(
@@ -62,20 +261,51 @@ impl Session {
                         .await
                     {
                         warn!(target: "session", "add transceiver error: {}", e);
-                        addr.do_send(CloseSession);
+                        addr.do_send(CloseSession(CloseReason::TransceiverError));
                         return;
                     }
 
+                    match peer_connection
+                        .create_data_channel(CONTROL_CHANNEL_LABEL, Some(control_channel_init()))
+                        .await
+                    {
+                        Ok(channel) => {
+                            let control_addr = addr.clone();
+                            channel
+                                .on_message(Box::new(move |message: DataChannelMessage| {
+                                    let request = serde_json::from_slice::<ControlRequest>(&message.data)
+                                        .map_err(|e| e.to_string());
+                                    control_addr.do_send(ControlMessage(request));
+                                    Box::pin(async {})
+                                }))
+                                .await;
+                            addr.do_send(AcceptControl(channel));
+                        }
+                        Err(e) => warn!(target: "session", "create control channel error: {}", e),
+                    }
+
                     peer_connection
                         .on_track({
                             let addr = addr.clone();
                             Box::new(
                                 move |track: Option<Arc<TrackRemote>>,
-                                      _receiver: Option<Arc<RTCRtpReceiver>>| {
+                                      receiver: Option<Arc<RTCRtpReceiver>>| {
                                     let addr = addr.clone();
                                     match track {
                                         Some(track) => Box::pin(async move {
-                                            if let Err(e) = addr.send(AcceptRemote(track)).await {
+                                            let parameters = track.codec().await;
+                                            let format = match TrackFormat::from_parameters(&parameters) {
+                                                Some(f) => f,
+                                                None => {
+                                                    warn!(target: "session", "unsupported codec of track: {}", parameters.capability.mime_type);
+                                                    return;
+                                                }
+                                            };
+                                            let audio_level = match receiver {
+                                                Some(r) => get_audio_level_id(&r).await,
+                                                None => None,
+                                            };
+                                            if let Err(e) = addr.send(AcceptRemote(track, format, audio_level)).await {
                                                 warn!(target: "session", "fail to send remote: {}", e)
                                             }
                                         }),
@@ -86,19 +316,39 @@ impl Session {
                         })
                         .await;
 
+                    peer_connection
+                        .on_ice_candidate({
+                            let addr = addr.clone();
+                            Box::new(move |candidate: Option<RTCIceCandidate>| {
+                                let addr = addr.clone();
+                                Box::pin(async move {
+                                    let candidate = match candidate {
+                                        Some(c) => match c.to_json().await {
+                                            Ok(c) => Some(c),
+                                            Err(e) => {
+                                                warn!(target: "session", "fail to serialize local candidate: {}", e);
+                                                return;
+                                            }
+                                        },
+                                        None => None,
+                                    };
+                                    addr.do_send(LocalCandidate(candidate));
+                                })
+                            })
+                        })
+                        .await;
+
                     peer_connection
                         .on_ice_connection_state_change(Box::new(move |connection_state: RTCIceConnectionState| {
                             debug!(target: "session", "connection state has changed {}", connection_state);
 
-                            let addr = addr.clone();
+                            events.do_send(Publish(id, SessionEvent::IceStateChanged {
+                                state: connection_state.to_string(),
+                            }));
 
-                            Box::pin(async move {
-                                if matches!(connection_state, RTCIceConnectionState::Failed | RTCIceConnectionState::Disconnected) {
-                                    if let Err(e) = addr.send(CloseSession).await {
-                                        warn!(target: "session", "fail to close session: {}", e)
-                                    }
-                                }
-                            })
+                            addr.do_send(IceConnectionState(connection_state));
+
+                            Box::pin(async {})
                         }))
                         .await;
                 }
@@ -124,25 +374,119 @@ impl Actor for Session {
     }
 
     fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
+        if self.finalized {
+            return Running::Stop;
+        }
+        if self.closing {
+            return Running::Continue;
+        }
+        self.closing = true;
+
         debug!(target: "session", "session closing");
-        if let Err(e) = self.writer.close() {
-            error!(target: "session", "close ogg writer error: {}", e);
+        for track in 0..self.tracks.len() {
+            let frames = self.tracks[track].jitter.finish();
+            self.write_frames(track, frames, ctx);
+        }
+        let stats = self
+            .tracks
+            .iter()
+            .map(|t| t.jitter.stats())
+            .collect::<Vec<_>>();
+        debug!(target: "session", "tracks stats: {:?}", stats);
+
+        for recording in &mut self.tracks {
+            if let Some(Err(e)) = recording.writer.take().map(AudioWriter::close) {
+                error!(target: "session", "close audio writer error: {}", e);
+            }
+        }
+
+        // texts of the segments can't be sent after the session is closed
+        self.live = None;
+        let unfinished = self
+            .tracks
+            .iter_mut()
+            .filter_map(|t| t.segmenter.take()?.finish())
+            .collect::<Vec<_>>();
+        for segment in unfinished
+            .into_iter()
+            .chain(std::mem::take(&mut self.segments))
+        {
+            drop(self.segment_file(segment));
+        }
+        for (_, recognition) in std::mem::take(&mut self.recognitions) {
+            ctx.cancel_future(recognition);
+        }
+
+        self.publish(SessionEvent::SessionClosed {
+            reason: self.close_reason.unwrap_or(CloseReason::Stopped),
+            tracks: stats,
+        });
+
+        self.garbage_collector
+            .do_send(ClearSession(self.user_id, self.id));
+
+        // control channel is kept open until the requested text is sent
+        let recognition = match (self.recognize, self.asr.clone(), self.control.take()) {
+            (Some((speech_model, track)), Some(launcher), Some(control)) => Some((
+                launcher,
+                AsrRequest {
+                    user_id: self.user_id,
+                    session_id: self.id,
+                    track,
+                    speech_model,
+                    callback: None,
+                },
+                control,
+            )),
+            (_, _, control) => {
+                self.close_peer_connection(control);
+                None
+            }
+        };
+
+        // recordings are available after they're converted to the requested format and channels
+        let conversions = (0..self.tracks.len())
+            .filter_map(|track| match self.convert_recording(track) {
+                Some(conversion) => Some(conversion),
+                None => {
+                    self.account_recording(track);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        if conversions.is_empty() && recognition.is_none() {
+            self.finalized = true;
+            return Running::Stop;
         }
 
-        let pc = self.peer_connection.clone();
         ctx.spawn(
             async move {
-                if let Err(e) = pc.close().await {
-                    warn!(target: "session", "close peer connection error: {}", e);
+                futures::future::join_all(conversions).await;
+
+                if let Some((launcher, request, control)) = recognition {
+                    control.notify(recognize_recording(launcher, request).await);
+                    Some(control)
+                } else {
+                    None
                 }
             }
-            .into_actor(self),
+            .into_actor(self)
+            .map(|control, session, ctx| {
+                if control.is_some() {
+                    session.close_peer_connection(control);
+                }
+                session.finalized = true;
+                ctx.stop()
+            }),
         );
 
-        self.garbage_collector
-            .do_send(ClearSession(self.user_id, self.id));
+        Running::Continue
+    }
 
-        Running::Stop
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        for waiter in std::mem::take(&mut self.close_waiters) {
+            let _ = waiter.send(());
+        }
     }
 }
 
@@ -151,13 +495,49 @@ impl Handler<AcceptRemote> for Session {
 
     fn handle(
         &mut self,
-        AcceptRemote(track): AcceptRemote,
+        AcceptRemote(track, format, audio_level): AcceptRemote,
         ctx: &mut Self::Context,
     ) -> Self::Result {
-        debug!(target: "session", "handle track: {:#?}", track);
-        ctx.add_stream(futures::stream::unfold(track, move |track| async {
+        debug!(target: "session", "handle track ({:?}): {:#?}", format, track);
+
+        // writer of the first track is prepared before the track is received
+        let index = if self.tracks[0].ssrc.is_none() {
+            if format != self.tracks[0].format {
+                self.replace_writer(format);
+            }
+            0
+        } else {
+            let writer = AudioWriter::create(format, self.id, self.tracks.len(), self.dir.clone());
+            if let Err(e) = &writer {
+                error!(target: "session", "fail to create audio writer for {:?}: {}", format, e)
+            }
+            let segmenter = self.create_segmenter(self.tracks.len(), format);
+            self.tracks.push(TrackRecording {
+                format,
+                ssrc: None,
+                writer: writer.ok(),
+                written: 0,
+                segmenter,
+                jitter: JitterBuffer::new(self.jitter_buffer),
+                rebase: TimestampRebase::default(),
+            });
+            self.tracks.len() - 1
+        };
+        self.tracks[index].ssrc = Some(track.ssrc());
+        if let Some(segmenter) = &mut self.tracks[index].segmenter {
+            segmenter.set_audio_level(audio_level);
+        }
+
+        self.publish(SessionEvent::TrackAdded {
+            track: index,
+            codec: format.codec,
+            channels: format.channels,
+            ssrc: track.ssrc(),
+        });
+
+        ctx.add_stream(futures::stream::unfold(track, move |track| async move {
             match track.read_rtp().await {
-                Ok((p, _)) => Some((RtpPacket(p), track)),
+                Ok((p, _)) => Some((RtpPacket(index, p), track)),
                 Err(e) => {
                     warn!(target: "session", "read rtp error: {}", e);
                     None
@@ -167,46 +547,621 @@ impl Handler<AcceptRemote> for Session {
     }
 }
 
+/// Recognizes the recording requested over the control channel, the result is sent back to the client.
+async fn recognize_recording(launcher: AsrLauncher, request: AsrRequest) -> ControlNotification {
+    let track = request.track;
+    let Some(processor) = launcher.launch(request) else {
+        return ControlNotification::error("track wasn't recorded");
+    };
+
+    let result = match processor.send(WaitForResponse).await {
+        Ok(ProcessResponse(rx)) => rx.await.ok(),
+        Err(_) => None,
+    };
+
+    match result.as_deref() {
+        Some(Ok(text)) => ControlNotification::Event(SessionEvent::AsrFinished {
+            track,
+            text: text.clone(),
+        }),
+        Some(Err(error)) => ControlNotification::Event(SessionEvent::AsrFailed {
+            track,
+            error: error.clone(),
+        }),
+        None => ControlNotification::error("recognition is interrupted"),
+    }
+}
+
+impl Session {
+    /// Publishes the event to subscribers and sends it over the control channel.
+    fn publish(&self, event: SessionEvent) {
+        if let Some(control) = &self.control {
+            control.notify(ControlNotification::Event(event.clone()));
+        }
+        self.events.do_send(Publish(self.id, event));
+    }
+
+    /// Peer connection is closed after the queued notifications are sent over the control channel.
+    fn close_peer_connection(&self, control: Option<ControlQueue>) {
+        let pc = self.peer_connection.clone();
+        actix_web::rt::spawn(async move {
+            if let Some(control) = control {
+                control.close().await;
+            }
+            if let Err(e) = pc.close().await {
+                warn!(target: "session", "close peer connection error: {}", e);
+            }
+        });
+    }
+
+    /// Recording of the first track is started as mono Ogg/Opus,
+    /// it's replaced by the container and channels of the negotiated track.
+    fn replace_writer(&mut self, format: TrackFormat) {
+        let recording = &mut self.tracks[0];
+        if let Some(Err(e)) = recording.writer.take().map(AudioWriter::close) {
+            warn!(target: "session", "close audio writer error: {}", e);
+        }
+        if let Err(e) = std::fs::remove_file(get_recorded_path(
+            recording.format.codec,
+            self.id,
+            0,
+            self.dir.clone(),
+        )) {
+            warn!(target: "session", "fail to remove previous recording: {}", e);
+        }
+
+        match AudioWriter::create(format, self.id, 0, self.dir.clone()) {
+            Ok(w) => recording.writer = Some(w),
+            Err(e) => {
+                error!(target: "session", "fail to create audio writer for {:?}: {}", format, e)
+            }
+        }
+        recording.format = format;
+
+        self.tracks[0].segmenter = self.create_segmenter(0, format);
+    }
+
+    /// Tracks are segmented when the speech is recognized live, only Opus can be segmented.
+    fn create_segmenter(&self, track: usize, format: TrackFormat) -> Option<Segmenter> {
+        self.live.as_ref()?;
+        if format.codec != AudioCodec::Opus {
+            warn!(target: "session", "live recognition supports only opus, {:?} is received", format.codec);
+            return None;
+        }
+        Some(Segmenter::new(
+            self.id,
+            track,
+            self.dir.clone(),
+            self.segment,
+        ))
+    }
+
+    /// Writes packets released by the jitter buffer, lost packets are replaced by silence.
+    fn write_frames(&mut self, track: usize, frames: Vec<Frame>, ctx: &mut Context<Self>) {
+        for frame in frames {
+            // nothing is written after the quota is exceeded
+            if self.close_reason.is_some_and(|r| r.is_quota_exceeded()) {
+                return;
+            }
+
+            if self.paused {
+                continue;
+            }
+
+            let recording = &mut self.tracks[track];
+            let mut packet = match frame {
+                Frame::Packet(p) => p,
+                Frame::Lost {
+                    sequence_number,
+                    timestamp,
+                    duration,
+                } => match recording.format.silence(duration) {
+                    Some(payload) => Packet {
+                        header: Header {
+                            sequence_number,
+                            timestamp,
+                            ssrc: recording.ssrc.unwrap_or_default(),
+                            ..Default::default()
+                        },
+                        payload: payload.into(),
+                    },
+                    None => continue,
+                },
+            };
+            packet.header.timestamp = recording.rebase.rebase(packet.header.timestamp);
+
+            match recording.writer.as_mut().map(|w| w.write_rtp(&packet)) {
+                Some(Ok(bytes)) => {
+                    recording.written += bytes;
+                    if let Err(exceeded) = self.quota.record(self.user_id, self.id, bytes) {
+                        warn!(target: "session", "{:?} quota is exceeded", exceeded);
+                        self.close_reason.get_or_insert(exceeded.into());
+                        ctx.stop();
+                    }
+                }
+                Some(Err(e)) => warn!(target: "session", "write rtp error: {}", e),
+                None => {}
+            }
+
+            let Some(segmenter) = self.tracks[track].segmenter.as_mut() else {
+                continue;
+            };
+            // segments are stored next to the recordings until they're recognized
+            let (bytes, segment) = segmenter.write_rtp(&packet);
+            if let Err(exceeded) = self.quota.record(self.user_id, self.id, bytes) {
+                warn!(target: "session", "{:?} quota is exceeded by live segments", exceeded);
+                self.close_reason.get_or_insert(exceeded.into());
+                ctx.stop();
+            }
+            if let Some(segment) = segment {
+                self.recognize_segment(segment, ctx);
+            }
+        }
+    }
+
+    /// Counts the recording of the track by its size on the disk instead of the written bytes.
+    fn account_recording(&self, track: usize) {
+        let TrackRecording {
+            format, written, ..
+        } = self.tracks[track];
+        let path = get_recorded_path(format.codec, self.id, track, self.dir.clone());
+        match std::fs::metadata(&path) {
+            Ok(m) => {
+                if let Err(exceeded) = self.quota.resize(self.user_id, self.id, written, m.len()) {
+                    warn!(target: "session", "{:?} quota is exceeded by the closed recording", exceeded);
+                }
+            }
+            Err(e) => debug!(target: "session", "fail to get size of {:?}: {}", path, e),
+        }
+    }
+
+    /// Returns conversion of the track recording if it isn't in the requested format and channels.
+    /// The converted file is counted by the quota, the recording is kept as is
+    /// when the conversion crosses a limit and the recorded format can be served.
+    fn convert_recording(&self, track: usize) -> Option<impl std::future::Future<Output = ()>> {
+        let TrackRecording {
+            format: TrackFormat { codec, channels },
+            written,
+            ..
+        } = self.tracks[track];
+        let format = self.format.unwrap_or_else(|| codec.default_format());
+        let downmix = self.mono && channels > 1;
+        if codec.recorded_format() == Some(format) && !downmix {
+            return None;
+        }
+
+        let transcoder = self.transcoder.clone();
+        let quota = self.quota.clone();
+        let (user_id, id) = (self.user_id, self.id);
+        let recorded_path = get_recorded_path(codec, self.id, track, self.dir.clone());
+        let input_format = (codec == AudioCodec::G722).then_some("g722");
+        let output = get_audio_path(self.id, track, format, self.dir.clone());
+        // output can be the recorded file itself, so it's written aside first
+        let converted = output.with_extension(format!("converted.{}", format.extension()));
+
+        Some(async move {
+            debug!(target: "session", "converting recording {:?} to {:?}", recorded_path, format);
+            let result = transcoder
+                .convert(
+                    recorded_path.clone(),
+                    input_format,
+                    converted.clone(),
+                    format,
+                    downmix.then_some(1),
+                )
+                .await
+                .and_then(|_| std::fs::metadata(&converted))
+                .and_then(|m| match quota.resize(user_id, id, written, m.len()) {
+                    Err(exceeded) if codec.recorded_format().is_some() => {
+                        warn!(target: "session", "{:?} quota is exceeded by the converted recording, it's kept as {:?}", exceeded, codec.recorded_format());
+                        let recorded = std::fs::metadata(&recorded_path)?.len();
+                        let _ = quota.resize(user_id, id, m.len(), recorded);
+                        std::fs::remove_file(&converted)
+                    }
+                    result => {
+                        if let Err(exceeded) = result {
+                            warn!(target: "session", "{:?} quota is exceeded by the decoded recording", exceeded);
+                        }
+                        std::fs::remove_file(&recorded_path)
+                            .and_then(|_| std::fs::rename(&converted, &output))
+                    }
+                });
+
+            if let Err(e) = result {
+                error!(target: "session", "fail to convert recording to {:?}: {}", format, e);
+            }
+        })
+    }
+
+    /// Segment is queued, its text is published unless the session is closed before it.
+    fn recognize_segment(&mut self, segment: FinishedSegment, ctx: &mut Context<Self>) {
+        self.segments.push_back(segment);
+        if self.segments.len() > MAX_QUEUED_SEGMENTS {
+            if let Some(dropped) = self.segments.pop_front() {
+                warn!(target: "session", "segment {} of track {} of {} is dropped, recognition falls behind", dropped.index, dropped.track, self.id);
+                drop(self.segment_file(dropped));
+            }
+        }
+        self.recognize_next_segments(ctx);
+    }
+
+    /// Segment file is removed and its bytes are freed when it's dropped.
+    fn segment_file(&self, segment: FinishedSegment) -> SegmentFile {
+        SegmentFile {
+            path: segment.path,
+            bytes: segment.bytes,
+            quota: self.quota.clone(),
+            user_id: self.user_id,
+            session_id: self.id,
+        }
+    }
+
+    /// Starts recognitions of queued segments until `MAX_SEGMENT_RECOGNITIONS` are in flight.
+    fn recognize_next_segments(&mut self, ctx: &mut Context<Self>) {
+        let Some(live) = &self.live else {
+            return;
+        };
+        let recognizer = live.recognizer.clone();
+        let speech_model = live.speech_model;
+        let deadline = live.deadline;
+
+        while self.recognitions.len() < MAX_SEGMENT_RECOGNITIONS {
+            let Some(segment) = self.segments.pop_front() else {
+                return;
+            };
+            let (track, index) = (segment.track, segment.index);
+            let id = self.id;
+            let recognizer = recognizer.clone();
+            let progress = ctx.address().recipient();
+            // segment is removed also when its recognition is cancelled
+            let segment = self.segment_file(segment);
+
+            debug!(target: "session", "recognizing segment {} of track {} of {}", index, track, id);
+            let handle = ctx.spawn(
+                async move {
+                    let result = actix_web::rt::time::timeout(
+                        deadline,
+                        recognizer.recognize(segment.path.clone(), speech_model, progress),
+                    )
+                    .await
+                    .unwrap_or(Err(AsrError::Timeout));
+                    drop(segment);
+
+                    match result {
+                        Ok(text) => SessionEvent::AsrPartial {
+                            track,
+                            segment: index,
+                            text,
+                        },
+                        Err(error) => {
+                            warn!(target: "session", "fail to recognize segment {} of track {} of {}: {}", index, track, id, error);
+                            SessionEvent::AsrPartialFailed {
+                                track,
+                                segment: index,
+                                error,
+                            }
+                        }
+                    }
+                }
+                .into_actor(self)
+                .map(move |event, session, ctx| {
+                    session.recognitions.remove(&(track, index));
+                    session.publish(event);
+                    session.recognize_next_segments(ctx);
+                }),
+            );
+            self.recognitions.insert((track, index), handle);
+        }
+    }
+}
+
+/// Id of the audio level header extension negotiated for the received track.
+async fn get_audio_level_id(receiver: &RTCRtpReceiver) -> Option<u8> {
+    receiver
+        .get_parameters()
+        .await
+        .header_extensions
+        .into_iter()
+        .find(|e| e.uri == AUDIO_LEVEL_URI)
+        .and_then(|e| u8::try_from(e.id).ok())
+}
+
+/// Segment file counted by the quota.
+struct SegmentFile {
+    path: PathBuf,
+    bytes: u64,
+    quota: Arc<StorageQuota>,
+    user_id: UserId,
+    session_id: Uuid,
+}
+
+impl Drop for SegmentFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!(target: "session", "fail to remove segment {:?}: {}", self.path, e);
+        }
+        self.quota.free(self.user_id, self.session_id, self.bytes);
+    }
+}
+
 impl StreamHandler<RtpPacket> for Session {
-    fn handle(&mut self, RtpPacket(packet): RtpPacket, _ctx: &mut Self::Context) {
+    fn handle(&mut self, RtpPacket(track, packet): RtpPacket, ctx: &mut Self::Context) {
         if packet.payload.is_empty() {
             return;
         }
 
-        trace!(target: "session", "process packet {:#?}", packet);
-        if let Err(e) = self.writer.write_rtp(&packet) {
-            warn!(target: "session", "write rtp error: {}", e);
+        trace!(target: "session", "process packet of track {} {:#?}", track, packet);
+        let Some(recording) = self.tracks.get_mut(track) else {
+            return;
+        };
+        let frames = recording.jitter.push(packet);
+        self.write_frames(track, frames, ctx);
+        self.keep_alive.refresh();
+
+        if !self.first_packet_received {
+            self.first_packet_received = true;
+            self.publish(SessionEvent::FirstPacket);
         }
-        self.update_time = Instant::now();
     }
 }
 
 impl StreamHandler<TimeoutChecks> for Session {
     fn handle(&mut self, _: TimeoutChecks, ctx: &mut Self::Context) {
         let startup_time_left = self.startup.elapsed();
-        let last_update_time_left = self.update_time.elapsed();
+        let last_update_time_left = self.keep_alive.update_time.elapsed();
         debug!(
             target: "session",
             "checking timeout(startup: {}ms, last update: {}ms)",
             startup_time_left.as_millis(),
             last_update_time_left.as_millis()
         );
-        if last_update_time_left > self.timeout || startup_time_left > self.total_timeout {
-            ctx.notify(CloseSession)
+        if startup_time_left > self.total_timeout {
+            ctx.notify(CloseSession(CloseReason::TotalTimeout))
+        } else if self.keep_alive.is_expired() {
+            ctx.notify(CloseSession(CloseReason::KeepAliveTimeout))
+        } else if let Err(exceeded) = self.quota.check_free_space() {
+            warn!(target: "session", "{:?} quota is exceeded, the disk is running out", exceeded);
+            ctx.notify(CloseSession(exceeded.into()))
         }
     }
 }
 
-impl Handler<CloseSession> for Session {
+/// Steps of segments recognition aren't reported, only results are published.
+impl Handler<AsrProgress> for Session {
     type Result = ();
 
-    fn handle(&mut self, _msg: CloseSession, ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, progress: AsrProgress, _ctx: &mut Self::Context) {
+        trace!(target: "session", "segment recognition progress {:?}", progress);
+    }
+}
+
+impl Handler<CloseSession> for Session {
+    type Result = MessageResult<CloseSession>;
+
+    fn handle(
+        &mut self,
+        CloseSession(reason): CloseSession,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
         debug!(
             target: "session",
-            "stopped from close message, total time: {}ms",
+            "stopped from close message ({:?}), total time: {}ms",
+            reason,
             self.startup.elapsed().as_millis()
         );
-        ctx.stop()
+        self.close_reason.get_or_insert(reason);
+
+        let (tx, rx) = futures::channel::oneshot::channel();
+        self.close_waiters.push(tx);
+        ctx.stop();
+
+        MessageResult(Closed(rx))
+    }
+}
+
+impl Handler<PauseRecording> for Session {
+    type Result = ();
+
+    fn handle(&mut self, _: PauseRecording, ctx: &mut Self::Context) -> Self::Result {
+        if self.paused {
+            return;
+        }
+        debug!(target: "session", "recording is paused");
+        self.paused = true;
+
+        // speech before the pause is recognized without waiting for the silence
+        let segments = self
+            .tracks
+            .iter_mut()
+            .filter_map(|t| t.segmenter.as_mut()?.finish())
+            .collect::<Vec<_>>();
+        for segment in segments {
+            self.recognize_segment(segment, ctx);
+        }
+
+        self.publish(SessionEvent::RecordingPaused);
+    }
+}
+
+impl Handler<ResumeRecording> for Session {
+    type Result = ();
+
+    fn handle(&mut self, _: ResumeRecording, _ctx: &mut Self::Context) -> Self::Result {
+        if !self.paused {
+            return;
+        }
+        debug!(target: "session", "recording is resumed");
+        self.paused = false;
+        for recording in &mut self.tracks {
+            recording.rebase.resumed = true;
+        }
+
+        self.publish(SessionEvent::RecordingResumed);
+    }
+}
+
+impl Handler<HasTrack> for Session {
+    type Result = bool;
+
+    fn handle(&mut self, HasTrack(track): HasTrack, _ctx: &mut Self::Context) -> Self::Result {
+        track < self.tracks.len()
+    }
+}
+
+impl Handler<AcceptControl> for Session {
+    type Result = ();
+
+    fn handle(&mut self, AcceptControl(channel): AcceptControl, _ctx: &mut Self::Context) {
+        debug!(target: "session", "control channel is created");
+        self.control = Some(ControlQueue::new(channel));
+    }
+}
+
+impl Handler<ControlMessage> for Session {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        ControlMessage(request): ControlMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        debug!(target: "session", "control request: {:?}", request);
+
+        let request = match request {
+            Ok(r) => r,
+            Err(e) => {
+                if let Some(control) = &self.control {
+                    control.notify(ControlNotification::error(e));
+                }
+                return;
+            }
+        };
+
+        match request {
+            ControlRequest::Resume => ctx.notify(ResumeRecording),
+            ControlRequest::Pause => ctx.notify(PauseRecording),
+            ControlRequest::Stop => {
+                self.close_reason.get_or_insert(CloseReason::Stopped);
+                ctx.stop();
+            }
+            ControlRequest::Recognize { .. } if self.asr.is_none() => {
+                if let Some(control) = &self.control {
+                    control.notify(ControlNotification::error("recognition isn't available"));
+                }
+            }
+            ControlRequest::Recognize { track, .. } if track >= self.tracks.len() => {
+                if let Some(control) = &self.control {
+                    control.notify(ControlNotification::error("track wasn't recorded"));
+                }
+            }
+            ControlRequest::Recognize { speech, track } => {
+                self.recognize = Some((speech, track));
+                self.close_reason.get_or_insert(CloseReason::Recognition);
+                ctx.stop();
+            }
+            ControlRequest::KeepAlive => self.keep_alive.refresh(),
+        }
+    }
+}
+
+impl Handler<LocalCandidate> for Session {
+    type Result = ();
+
+    fn handle(&mut self, LocalCandidate(candidate): LocalCandidate, _ctx: &mut Self::Context) {
+        match candidate {
+            Some(candidate) => {
+                trace!(target: "session", "local candidate gathered: {:?}", candidate);
+                self.local_candidates.push(candidate.clone());
+                self.publish(SessionEvent::IceCandidate { candidate });
+            }
+            None => {
+                debug!(target: "session", "gathering of local candidates is complete");
+                self.gathering_complete = true;
+                self.publish(SessionEvent::IceGatheringComplete);
+            }
+        }
+    }
+}
+
+impl Handler<GetLocalCandidates> for Session {
+    type Result = MessageResult<GetLocalCandidates>;
+
+    fn handle(&mut self, _: GetLocalCandidates, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(LocalCandidates {
+            candidates: self.local_candidates.clone(),
+            complete: self.gathering_complete,
+        })
+    }
+}
+
+impl Handler<AddRemoteCandidate> for Session {
+    type Result = MessageResult<AddRemoteCandidate>;
+
+    fn handle(
+        &mut self,
+        AddRemoteCandidate(candidate): AddRemoteCandidate,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let (tx, rx) = futures::channel::oneshot::channel();
+        let peer_connection = self.peer_connection.clone();
+        ctx.spawn(
+            async move {
+                let _ = tx.send(
+                    peer_connection
+                        .add_ice_candidate(candidate)
+                        .await
+                        .map_err(std::io::Error::other),
+                );
+            }
+            .into_actor(self),
+        );
+
+        MessageResult(CandidateResponse(rx))
+    }
+}
+
+impl Handler<IceConnectionState> for Session {
+    type Result = ();
+
+    fn handle(&mut self, IceConnectionState(state): IceConnectionState, ctx: &mut Self::Context) {
+        let reason = match state {
+            RTCIceConnectionState::Failed => CloseReason::IceFailed,
+            RTCIceConnectionState::Disconnected => CloseReason::IceDisconnected,
+            RTCIceConnectionState::Connected | RTCIceConnectionState::Completed => {
+                if let Some((_, handle)) = self.ice_lost.take() {
+                    debug!(target: "session", "ice connection is restored");
+                    ctx.cancel_future(handle);
+                    self.keep_alive.resume();
+                    // packets lost while disconnected aren't waited, the recording continues without a gap
+                    for track in &mut self.tracks {
+                        track.rebase.resumed = true;
+                    }
+                }
+                return;
+            }
+            _ => return,
+        };
+
+        match &mut self.ice_lost {
+            // failed after disconnected, the grace period isn't extended
+            Some((lost, _)) => *lost = reason,
+            None if self.ice_grace_period.is_zero() => ctx.notify(CloseSession(reason)),
+            None => {
+                debug!(
+                    target: "session",
+                    "ice connection is lost ({:?}), waiting for restart {}ms",
+                    reason,
+                    self.ice_grace_period.as_millis()
+                );
+                self.keep_alive.suspend();
+                let handle = ctx.run_later(self.ice_grace_period, |act, ctx| {
+                    if let Some((reason, _)) = act.ice_lost.take() {
+                        ctx.notify(CloseSession(reason));
+                    }
+                });
+                self.ice_lost = Some((reason, handle));
+            }
+        }
     }
 }
 
@@ -215,9 +1170,17 @@ impl Handler<OfferRequest> for Session {
 
     fn handle(
         &mut self,
-        OfferRequest(offer): OfferRequest,
+        OfferRequest(offer, trickle): OfferRequest,
         ctx: &mut Self::Context,
     ) -> Self::Result {
+        // candidates are gathered again only by ICE restart, other offers keep them
+        let remote_ice = ice_credentials(&offer);
+        if remote_ice != self.remote_ice {
+            self.local_candidates.clear();
+            self.gathering_complete = false;
+            self.remote_ice = remote_ice;
+        }
+
         let (tx, rx) = futures::channel::oneshot::channel();
         let peer_connection = self.peer_connection.clone();
         ctx.spawn(
@@ -231,24 +1194,18 @@ impl Handler<OfferRequest> for Session {
 
                     peer_connection.set_local_description(answer).await?;
 
-                    let _ = gather_complete.recv().await;
+                    // candidates of the trickled answer are fetched later
+                    if !trickle {
+                        let _ = gather_complete.recv().await;
+                    }
 
                     Ok(peer_connection.local_description().await)
                 }
                 .await;
 
-                let _ = tx.send(
-                    response
-                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
-                        .and_then(|ld| {
-                            ld.ok_or_else(|| {
-                                std::io::Error::new(
-                                    std::io::ErrorKind::Other,
-                                    "generate local_description failed",
-                                )
-                            })
-                        }),
-                );
+                let _ = tx.send(response.map_err(std::io::Error::other).and_then(|ld| {
+                    ld.ok_or_else(|| std::io::Error::other("generate local_description failed"))
+                }));
             }
             .into_actor(self),
         );
@@ -257,26 +1214,167 @@ impl Handler<OfferRequest> for Session {
     }
 }
 
+/// ICE ufrag and pwd of the offer, they're the same in every media section of a bundled offer.
+fn ice_credentials(offer: &RTCSessionDescription) -> Option<(String, String)> {
+    let attribute = |name: &str| {
+        offer
+            .sdp
+            .lines()
+            .find_map(|l| l.trim().strip_prefix(name))
+            .map(str::to_owned)
+    };
+    Some((attribute("a=ice-ufrag:")?, attribute("a=ice-pwd:")?))
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct AcceptRemote(Arc<TrackRemote>, TrackFormat, Option<u8>);
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct RtpPacket(usize, Packet);
+
+#[derive(Message)]
+#[rtype(result = "Closed")]
+pub struct CloseSession(pub CloseReason);
+
 #[derive(Message)]
 #[rtype(result = "()")]
-struct AcceptRemote(Arc<TrackRemote>);
+struct AcceptControl(Arc<RTCDataChannel>);
 
+/// Request received over the control channel, or the reason it can't be parsed.
 #[derive(Message)]
 #[rtype(result = "()")]
-struct RtpPacket(Packet);
+struct ControlMessage(Result<ControlRequest, String>);
 
+/// Packets are received but not written until `ResumeRecording`, the session is kept alive.
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct CloseSession;
+pub struct PauseRecording;
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ResumeRecording;
+
+/// Whether the track with this number is being recorded.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct HasTrack(pub usize);
+
+/// Resolved when the session is stopped and its recording is finalized.
+pub struct Closed(pub futures::channel::oneshot::Receiver<()>);
+
+/// Why the session was closed, it's shown to the client.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// Speech recognition of the session was requested
+    Recognition,
+    /// No packets were received for `SessionConfig::timeout`
+    KeepAliveTimeout,
+    /// Session lived longer than `SessionConfig::total_timeout`
+    TotalTimeout,
+    IceFailed,
+    IceDisconnected,
+    TransceiverError,
+    Stopped,
+    /// Recording of the session crossed `QuotaLimits::session`
+    SessionQuotaExceeded,
+    /// Recordings of the user crossed `QuotaLimits::user`
+    UserQuotaExceeded,
+    /// Recordings of all users crossed `QuotaLimits::total` or the free space is below `QuotaLimits::min_free`
+    TotalQuotaExceeded,
+}
+
+impl CloseReason {
+    fn is_quota_exceeded(&self) -> bool {
+        matches!(
+            self,
+            CloseReason::SessionQuotaExceeded
+                | CloseReason::UserQuotaExceeded
+                | CloseReason::TotalQuotaExceeded
+        )
+    }
+}
+
+impl From<QuotaExceeded> for CloseReason {
+    fn from(exceeded: QuotaExceeded) -> Self {
+        match exceeded {
+            QuotaExceeded::Session => CloseReason::SessionQuotaExceeded,
+            QuotaExceeded::User => CloseReason::UserQuotaExceeded,
+            QuotaExceeded::Total => CloseReason::TotalQuotaExceeded,
+        }
+    }
+}
 
 #[derive(Message)]
 #[rtype(result = "()")]
 struct TimeoutChecks;
 
+#[derive(Message)]
+#[rtype(result = "()")]
+struct IceConnectionState(RTCIceConnectionState);
+
+/// Candidate gathered by the server, `None` when gathering is complete.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct LocalCandidate(Option<RTCIceCandidateInit>);
+
+#[derive(Message)]
+#[rtype(result = "LocalCandidates")]
+pub struct GetLocalCandidates;
+
+pub struct LocalCandidates {
+    pub candidates: Vec<RTCIceCandidateInit>,
+    pub complete: bool,
+}
+
+/// Trickled candidate of the client.
+#[derive(Message)]
+#[rtype(result = "CandidateResponse")]
+pub struct AddRemoteCandidate(pub RTCIceCandidateInit);
+
+pub struct CandidateResponse(pub futures::channel::oneshot::Receiver<std::io::Result<()>>);
+
+/// Offer of the client, the answer is returned without waiting for candidates when ICE is trickled.
+/// Offer of the connected session renegotiates it, ICE is restarted when its credentials are changed.
 #[derive(Message)]
 #[rtype(result = "OfferResponse")]
-pub struct OfferRequest(pub RTCSessionDescription);
+pub struct OfferRequest(pub RTCSessionDescription, pub bool);
 
 pub struct OfferResponse(
     pub futures::channel::oneshot::Receiver<std::io::Result<RTCSessionDescription>>,
 );
+
+#[cfg(test)]
+mod tests {
+    use crate::webrtc::session::{KeepAlive, TimestampRebase};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn paused_time_is_cut_from_timestamps() {
+        let mut rebase = TimestampRebase::default();
+
+        let before = [1000, 1960, 2920].map(|t| rebase.rebase(t));
+        rebase.resumed = true;
+        let after = [50920, 51880].map(|t| rebase.rebase(t));
+
+        assert_eq!(before, [1000, 1960, 2920]);
+        assert_eq!(after, [3880, 4840]);
+    }
+
+    #[test]
+    fn keep_alive_starts_over_after_ice_is_restored() {
+        let mut keep_alive = KeepAlive::new(Duration::from_secs(10));
+        keep_alive.suspend();
+        // ICE was lost longer than the timeout
+        keep_alive.update_time = Instant::now() - Duration::from_secs(20);
+        assert!(!keep_alive.is_expired());
+
+        keep_alive.resume();
+        assert!(!keep_alive.is_expired());
+
+        keep_alive.update_time = Instant::now() - Duration::from_secs(11);
+        assert!(keep_alive.is_expired());
+    }
+}
@@ -0,0 +1,357 @@
+use crate::audio::AudioFormat;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use uuid::Uuid;
+use webrtc::api::media_engine::{MIME_TYPE_G722, MIME_TYPE_OPUS, MIME_TYPE_PCMA, MIME_TYPE_PCMU};
+use webrtc::media::io::ogg_writer::OggWriter;
+use webrtc::media::io::Writer;
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecParameters;
+
+pub const OPUS_SAMPLE_RATE: u32 = 48000;
+/// rtpmap of Opus always declares 2 channels (RFC 7587), real channels are negotiated by `stereo` fmtp
+pub const OPUS_RTPMAP_CHANNELS: u16 = 2;
+const G711_SAMPLE_RATE: u32 = 8000;
+const OGG_PAGE_HEADER_SIZE: u64 = 27;
+
+/// Audio codecs negotiated with the client.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioCodec {
+    Opus,
+    Pcmu,
+    Pcma,
+    G722,
+}
+
+impl AudioCodec {
+    pub fn from_mime_type(mime_type: &str) -> Option<Self> {
+        [
+            (MIME_TYPE_OPUS, AudioCodec::Opus),
+            (MIME_TYPE_PCMU, AudioCodec::Pcmu),
+            (MIME_TYPE_PCMA, AudioCodec::Pcma),
+            (MIME_TYPE_G722, AudioCodec::G722),
+        ]
+        .into_iter()
+        .find(|(m, _)| m.eq_ignore_ascii_case(mime_type))
+        .map(|(_, c)| c)
+    }
+
+    /// Format written by `AudioWriter`, G.722 is written raw.
+    pub fn recorded_format(&self) -> Option<AudioFormat> {
+        match self {
+            AudioCodec::Opus => Some(AudioFormat::Ogg),
+            AudioCodec::Pcmu | AudioCodec::Pcma => Some(AudioFormat::Wav),
+            AudioCodec::G722 => None,
+        }
+    }
+
+    /// Format of the recording when the client hasn't chosen one.
+    pub fn default_format(&self) -> AudioFormat {
+        self.recorded_format().unwrap_or(AudioFormat::Wav)
+    }
+}
+
+/// Codec and channels of the remote track.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct TrackFormat {
+    pub codec: AudioCodec,
+    pub channels: u8,
+}
+
+impl TrackFormat {
+    /// Microphones are mono, so it's expected before the track is received.
+    pub const DEFAULT: TrackFormat = TrackFormat {
+        codec: AudioCodec::Opus,
+        channels: 1,
+    };
+
+    pub fn from_parameters(parameters: &RTCRtpCodecParameters) -> Option<Self> {
+        let codec = AudioCodec::from_mime_type(&parameters.capability.mime_type)?;
+        let stereo = parameters
+            .capability
+            .sdp_fmtp_line
+            .split(';')
+            .any(|p| p.trim() == "stereo=1");
+
+        Some(Self {
+            codec,
+            channels: if codec == AudioCodec::Opus && stereo {
+                2
+            } else {
+                1
+            },
+        })
+    }
+}
+
+impl TrackFormat {
+    /// Payload of silence lasting `duration` RTP timestamp units, it fills the place of a lost packet.
+    /// G.722 gaps aren't filled.
+    pub fn silence(&self, duration: u32) -> Option<Vec<u8>> {
+        match self.codec {
+            AudioCodec::Opus => opus_silence(duration, self.channels > 1),
+            AudioCodec::Pcmu => Some(vec![0xFF; duration as usize]),
+            AudioCodec::Pcma => Some(vec![0xD5; duration as usize]),
+            AudioCodec::G722 => None,
+        }
+    }
+}
+
+/// Opus packet of silent CELT frames (RFC 6716, code 3 packet of equal frames).
+fn opus_silence(duration: u32, stereo: bool) -> Option<Vec<u8>> {
+    // packet can't be longer than 120ms
+    if duration == 0 || duration > OPUS_SAMPLE_RATE / 1000 * 120 {
+        return None;
+    }
+    // frame sizes at 48kHz of CELT fullband configs 31, 30, 29 and 28
+    let (config, frame) = [(31, 960), (30, 480), (29, 240), (28, 120)]
+        .into_iter()
+        .find(|(_, frame)| duration.is_multiple_of(*frame))?;
+    let count = duration / frame;
+
+    let toc = (config << 3) | ((stereo as u8) << 2) | 3;
+    let mut payload = vec![toc, count as u8];
+    for _ in 0..count {
+        payload.extend_from_slice(&[0xFF, 0xFE]);
+    }
+    Some(payload)
+}
+
+/// Writes RTP packets of the remote track to the recording file.
+/// Opus is stored to Ogg as is, G.711 is decoded to PCM WAV.
+/// G.722 payloads are stored raw and decoded by ffmpeg after the session is closed.
+pub enum AudioWriter {
+    Ogg(Box<OggWriter<File>>),
+    Wav(AudioCodec, hound::WavWriter<BufWriter<File>>),
+    G722(BufWriter<File>),
+}
+
+impl AudioWriter {
+    pub fn create(
+        format: TrackFormat,
+        uuid: Uuid,
+        track: usize,
+        dir: PathBuf,
+    ) -> std::io::Result<Self> {
+        let TrackFormat { codec, channels } = format;
+        Ok(match codec {
+            AudioCodec::Opus => AudioWriter::Ogg(Box::new(
+                OggWriter::new(
+                    File::create(get_audio_path(uuid, track, AudioFormat::Ogg, dir))?,
+                    OPUS_SAMPLE_RATE,
+                    channels,
+                )
+                .map_err(std::io::Error::other)?,
+            )),
+            AudioCodec::Pcmu | AudioCodec::Pcma => AudioWriter::Wav(
+                codec,
+                hound::WavWriter::create(
+                    get_audio_path(uuid, track, AudioFormat::Wav, dir),
+                    hound::WavSpec {
+                        channels: 1,
+                        sample_rate: G711_SAMPLE_RATE,
+                        bits_per_sample: 16,
+                        sample_format: hound::SampleFormat::Int,
+                    },
+                )
+                .map_err(std::io::Error::other)?,
+            ),
+            AudioCodec::G722 => AudioWriter::G722(BufWriter::new(File::create(get_g722_path(
+                uuid, track, dir,
+            ))?)),
+        })
+    }
+
+    /// Returns how many bytes the packet took in the file.
+    pub fn write_rtp(&mut self, packet: &Packet) -> std::io::Result<u64> {
+        let size = packet.payload.len() as u64;
+        match self {
+            AudioWriter::Ogg(w) => {
+                w.write_rtp(packet).map_err(std::io::Error::other)?;
+                Ok(ogg_packet_size(size))
+            }
+            AudioWriter::Wav(codec, w) => {
+                let decode = match codec {
+                    AudioCodec::Pcma => alaw_to_linear,
+                    _ => ulaw_to_linear,
+                };
+                packet
+                    .payload
+                    .iter()
+                    .try_for_each(|&s| w.write_sample(decode(s)))
+                    .map_err(std::io::Error::other)?;
+                Ok(size * 2)
+            }
+            AudioWriter::G722(w) => w.write_all(&packet.payload).map(|_| size),
+        }
+    }
+
+    pub fn close(self) -> std::io::Result<()> {
+        match self {
+            AudioWriter::Ogg(mut w) => w.close().map_err(std::io::Error::other),
+            AudioWriter::Wav(_, w) => w.finalize().map_err(std::io::Error::other),
+            AudioWriter::G722(mut w) => w.flush(),
+        }
+    }
+}
+
+/// Each packet is written to its own Ogg page with the lacing values of the payload.
+pub(super) fn ogg_packet_size(payload: u64) -> u64 {
+    OGG_PAGE_HEADER_SIZE + payload / 255 + 1 + payload
+}
+
+/// Recording of the first track is named by the session, other tracks have the number suffix.
+pub(super) fn file_stem(uuid: Uuid, track: usize) -> String {
+    match track {
+        0 => uuid.to_string(),
+        n => format!("{}.track{}", uuid, n),
+    }
+}
+
+/// Path of the track recording, format depends on the codec of the track or the client choice.
+pub fn get_audio_path(uuid: Uuid, track: usize, format: AudioFormat, mut dir: PathBuf) -> PathBuf {
+    dir.push(format!("{}.{}", file_stem(uuid, track), format.extension()));
+    dir
+}
+
+/// Finds the recording of the track, `None` if the track wasn't recorded.
+pub fn find_audio_path(uuid: Uuid, track: usize, dir: PathBuf) -> Option<(PathBuf, AudioFormat)> {
+    AudioFormat::ALL
+        .into_iter()
+        .map(|f| (get_audio_path(uuid, track, f, dir.clone()), f))
+        .find(|(p, _)| p.exists())
+}
+
+/// File written by `AudioWriter` for the codec.
+pub fn get_recorded_path(codec: AudioCodec, uuid: Uuid, track: usize, dir: PathBuf) -> PathBuf {
+    match codec.recorded_format() {
+        Some(f) => get_audio_path(uuid, track, f, dir),
+        None => get_g722_path(uuid, track, dir),
+    }
+}
+
+/// Every file the track can be stored to, whatever codec and format were chosen.
+pub fn get_track_paths(uuid: Uuid, track: usize, dir: PathBuf) -> Vec<PathBuf> {
+    let mut paths = AudioFormat::ALL
+        .into_iter()
+        .map(|f| get_audio_path(uuid, track, f, dir.clone()))
+        .collect::<Vec<_>>();
+    paths.push(get_g722_path(uuid, track, dir));
+    paths
+}
+
+/// Raw G.722 payloads waiting for decoding.
+pub fn get_g722_path(uuid: Uuid, track: usize, mut dir: PathBuf) -> PathBuf {
+    dir.push(format!("{}.g722", file_stem(uuid, track)));
+    dir
+}
+
+/// ITU-T G.711 μ-law expansion.
+fn ulaw_to_linear(sample: u8) -> i16 {
+    let sample = !sample;
+    let magnitude = ((((sample & 0x0F) as i16) << 3) + 0x84) << ((sample & 0x70) >> 4);
+    if sample & 0x80 != 0 {
+        0x84 - magnitude
+    } else {
+        magnitude - 0x84
+    }
+}
+
+/// ITU-T G.711 A-law expansion.
+fn alaw_to_linear(sample: u8) -> i16 {
+    let sample = sample ^ 0x55;
+    let segment = (sample & 0x70) >> 4;
+    let mantissa = ((sample & 0x0F) as i16) << 4;
+    let magnitude = match segment {
+        0 => mantissa + 8,
+        s => (mantissa + 0x108) << (s - 1),
+    };
+    if sample & 0x80 != 0 {
+        magnitude
+    } else {
+        -magnitude
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::webrtc::writer::{alaw_to_linear, ulaw_to_linear, AudioCodec, TrackFormat};
+    use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters};
+
+    fn parameters(mime_type: &str, sdp_fmtp_line: &str) -> RTCRtpCodecParameters {
+        RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: mime_type.to_string(),
+                sdp_fmtp_line: sdp_fmtp_line.to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn silence_fills_packet_duration() {
+        let opus = |channels| TrackFormat {
+            codec: AudioCodec::Opus,
+            channels,
+        };
+
+        assert_eq!(opus(1).silence(960), Some(vec![0xFB, 1, 0xFF, 0xFE]));
+        assert_eq!(
+            opus(2).silence(1920),
+            Some(vec![0xFF, 2, 0xFF, 0xFE, 0xFF, 0xFE])
+        );
+        assert_eq!(opus(1).silence(480), Some(vec![0xF3, 1, 0xFF, 0xFE]));
+        assert_eq!(opus(1).silence(100), None);
+
+        let pcmu = TrackFormat {
+            codec: AudioCodec::Pcmu,
+            channels: 1,
+        };
+        assert_eq!(pcmu.silence(160), Some(vec![0xFF; 160]));
+    }
+
+    #[test]
+    fn channels_follow_stereo_fmtp() {
+        let format = |mime_type, fmtp| TrackFormat::from_parameters(&parameters(mime_type, fmtp));
+
+        assert_eq!(
+            format("audio/opus", "minptime=10;useinbandfec=1"),
+            Some(TrackFormat {
+                codec: AudioCodec::Opus,
+                channels: 1
+            })
+        );
+        assert_eq!(
+            format("audio/opus", "minptime=10; stereo=1;sprop-stereo=1"),
+            Some(TrackFormat {
+                codec: AudioCodec::Opus,
+                channels: 2
+            })
+        );
+        assert_eq!(
+            format("audio/PCMU", "stereo=1"),
+            Some(TrackFormat {
+                codec: AudioCodec::Pcmu,
+                channels: 1
+            })
+        );
+        assert_eq!(format("audio/telephone-event", ""), None);
+    }
+
+    #[test]
+    fn g711_expansion() {
+        assert_eq!(ulaw_to_linear(0xFF), 0);
+        assert_eq!(ulaw_to_linear(0x7F), 0);
+        assert_eq!(ulaw_to_linear(0x80), 32124);
+        assert_eq!(ulaw_to_linear(0x00), -32124);
+
+        assert_eq!(alaw_to_linear(0xD5), 8);
+        assert_eq!(alaw_to_linear(0x55), -8);
+        assert_eq!(alaw_to_linear(0xAA), 32256);
+        assert_eq!(alaw_to_linear(0x2A), -32256);
+    }
+}